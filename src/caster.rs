@@ -1,59 +1,507 @@
 use eframe::egui;
-use crate::screen::{ScreenCapture, Frame, CropValues, crop, blank, available_displays};
-use crate:: server::StreamServer;
-pub struct Caster {
-    displays: Vec<String>,
-    capture: Option<ScreenCapture>, // Screen capture instance
-    server: StreamServer,
+use crate::screen::{ScreenCapture, CaptureError, Frame, CropValues, Resolution, MaskRegion, MaskStyle, DEFAULT_FPS, MAX_FPS};
+use crate::source::{DisplayInfo, FrameSource, ScrapSource, available_displays, display_thumbnail};
+use crate::window::{WindowInfo, WindowSource, available_windows};
+use crate::cursor::CursorMode;
+use crate::pipeline::{CastPipeline, CastSettings};
+use crate::masks::{SavedMasks, load_masks, save_masks};
+use crate::pattern::{Pattern, TestPatternSource};
+use crate::selection::{AspectLock, CropSelector};
+use crate::codec::{Codec, H264Settings, DEFAULT_QUALITY, MAX_DIMENSION};
+use crate:: server::{ChannelSender, IpVersion, ServerConfig, StreamServer};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// What the source picker can start
+#[derive(Clone)]
+enum SourceChoice {
+    Display(DisplayInfo),
+    Window(WindowInfo),
+    Pattern(Pattern, u32, u32),
+}
+
+impl SourceChoice {
+    // What privacy masks are saved under, the same for the same screen content
+    fn key(&self) -> String {
+        match self {
+            SourceChoice::Display(display) => display.id.clone(),
+            // Titles change with the open file or tab, the class doesn't
+            SourceChoice::Window(window) => match &window.class {
+                Some(class) => format!("window:{}", class),
+                None => window.title.clone(),
+            },
+            SourceChoice::Pattern(pattern, _, _) => pattern.name().to_string(),
+        }
+    }
+
+    // Whether the key could name different content next run, masks under it aren't saved
+    fn key_is_stable(&self) -> bool {
+        match self {
+            SourceChoice::Display(display) => display.stable,
+            SourceChoice::Window(window) => window.class.is_some(),
+            SourceChoice::Pattern(..) => true,
+        }
+    }
+}
+
+// One shared monitor, window or pattern, streamed on its own channel
+struct CastSource {
+    channel: String,
+    sender: ChannelSender,
+    key: String, // See SourceChoice::key
+    capture: ScreenCapture,
+    settings: Arc<Mutex<CastSettings>>, // Read by the capture thread, see sync_settings
     current_frame: Option<Frame>, // Current frame data to display
     crop: CropValues,
+    crop_selector: CropSelector,
+    error: Option<String>, // Why capturing stopped, the channel stays open
+}
+
+pub struct Caster {
+    displays: Vec<DisplayInfo>,
+    thumbnails: HashMap<String, egui::TextureHandle>, // By display id
+    pending_thumbnails: Option<mpsc::Receiver<(DisplayInfo, io::Result<Frame>)>>, // Still being taken in the background
+    displays_checked: Option<Instant>, // When the display list was last compared for hot-plugs
+    windows: Vec<WindowInfo>,
+    window: Option<u32>, // Window picked for sharing
+    sources: Vec<CastSource>,
+    selected: usize, // Source whose preview and crop are shown
+    error_message: Option<String>, // Why the last source failed to start
+    server: StreamServer,
+    trim_crop: bool, // Send only the cropped region instead of painting the margins white
+    resolution: Resolution, // Outgoing frames are scaled down to fit this
+    masks: SavedMasks,
+    unsaved_masks: HashSet<String>, // Keys that aren't stable, their masks only last this run
+    masks_changed: bool, // Edited since they were last saved
+    cursor_mode: CursorMode,
     is_streaming : bool,
     is_blank : bool,
+    pattern: Pattern,
+    pattern_size: (u32, u32),
+    codec: Codec,
+    quality: u8,
+    h264_settings: H264Settings,
+    server_config: ServerConfig,
+    server_status: Result<SocketAddr, String>, // Bound address, or why binding failed
+    fps: u32, // Target rate for both capturing and sending
 }
 
+const PATTERN_SIZES: [(u32, u32); 3] = [(640, 480), (1280, 720), (1920, 1080)];
+// How often to look for monitors that were plugged in or out
+const DISPLAY_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const THUMBNAIL_WIDTH: u32 = 160;
+
 impl Caster {
     // Initialize the Caster with a new ScreenCapture instance
     pub fn new() -> Self {
-        let capture = None;
-        let server = StreamServer::new();
-        let crop = CropValues::new(0.0, 0.0, 0.0, 0.0);
-        let displays = available_displays();
+        let mut server = StreamServer::new();
+        let server_config = ServerConfig::default();
+        let server_status = server.listen(&server_config);
         Self {
-            displays,
-            capture,
+            // Listed on the first render, which also starts taking the thumbnails
+            displays: Vec::new(),
+            thumbnails: HashMap::new(),
+            pending_thumbnails: None,
+            displays_checked: None,
+            windows: available_windows(),
+            window: None,
+            sources: Vec::new(),
+            selected: 0,
+            error_message: None,
             server,
+            trim_crop: true,
+            resolution: Resolution::Native,
+            masks: load_masks(),
+            unsaved_masks: HashSet::new(),
+            masks_changed: false,
+            cursor_mode: CursorMode::Hidden,
+            is_streaming: false,
+            is_blank: false,
+            pattern: Pattern::ColorBars,
+            pattern_size: (1280, 720),
+            codec: Codec::Raw,
+            quality: DEFAULT_QUALITY,
+            h264_settings: H264Settings::default(),
+            server_config,
+            server_status,
+            fps: DEFAULT_FPS,
+        }
+    }
+
+    // Start casting from any frame source, on a new channel named after it.
+    // key selects the privacy masks that apply.
+    pub fn start_capture<S, F>(&mut self, name: &str, key: &str, make_source: F) -> Result<(), CaptureError>
+    where
+        S: FrameSource,
+        F: FnOnce() -> std::io::Result<S> + Send + 'static,
+    {
+        let crop = CropValues::new(0.0, 0.0, 0.0, 0.0);
+        let settings = Arc::new(Mutex::new(self.cast_settings(key, &crop)));
+        let sender = self.server.add_channel(name);
+        let pipeline = CastPipeline::new(Arc::clone(&settings), sender.clone());
+        let capture = match ScreenCapture::new(make_source, self.fps, pipeline) {
+            Ok(capture) => capture,
+            Err(error) => {
+                self.server.remove_channel(sender.name());
+                return Err(error);
+            }
+        };
+        self.sources.push(CastSource {
+            channel: sender.name().to_string(),
+            sender,
+            key: key.to_string(),
+            capture,
+            settings,
             current_frame: None,
             crop,
-            is_streaming: false,
-            is_blank: false
+            crop_selector: CropSelector::new(),
+            error: None,
+        });
+        self.selected = self.sources.len() - 1;
+        Ok(())
+    }
+
+    // Stop a source and close its channel
+    fn stop_capture(&mut self, index: usize) {
+        let mut source = self.sources.remove(index);
+        source.capture.stop();
+        self.server.remove_channel(&source.channel);
+        self.selected = self.selected.min(self.sources.len().saturating_sub(1));
+    }
+
+    // Capture from a new source on an existing channel. Connected receivers
+    // stay on the channel and get the new source from its first frame.
+    fn switch_capture(&mut self, index: usize, choice: SourceChoice) {
+        let fps = self.fps;
+        let key = choice.key();
+        if !choice.key_is_stable() {
+            self.unsaved_masks.insert(key.clone());
+        }
+        // The old crop belongs to the old source, and the new one gets its own masks
+        let crop = CropValues::new(0.0, 0.0, 0.0, 0.0);
+        let settings = self.cast_settings(&key, &crop);
+        let source = &mut self.sources[index];
+        // Release the old source first, some platforms allow only one capturer per display
+        source.capture.stop();
+        source.key = key;
+        source.crop = crop;
+        *source.settings.lock().unwrap() = settings;
+        // Joining receivers must not be shown the old source, or its masks
+        self.server.reset_channel(&source.channel);
+        let pipeline = CastPipeline::new(Arc::clone(&source.settings), source.sender.clone());
+        let capture = match choice {
+            SourceChoice::Display(display) => ScreenCapture::new(move || ScrapSource::new(&display.id), fps, pipeline),
+            SourceChoice::Window(window) => ScreenCapture::new(move || WindowSource::new(window.id), fps, pipeline),
+            SourceChoice::Pattern(pattern, width, height) => {
+                ScreenCapture::new(move || TestPatternSource::new(pattern, width, height, MAX_FPS), fps, pipeline)
+            }
+        };
+        // The old preview belongs to the old source
+        source.current_frame = None;
+        match capture {
+            Ok(capture) => {
+                source.capture = capture;
+                source.error = None;
+            }
+            Err(error) => source.error = Some(error.to_string()),
+        }
+    }
+
+    fn start_choice(&mut self, choice: SourceChoice) {
+        let key = choice.key();
+        if !choice.key_is_stable() {
+            self.unsaved_masks.insert(key.clone());
+        }
+        let result = match choice {
+            SourceChoice::Display(display) => self.start_capture(&display.name, &key, move || ScrapSource::new(&display.id)),
+            SourceChoice::Window(window) => self.start_capture(&window.title, &key, move || WindowSource::new(window.id)),
+            SourceChoice::Pattern(pattern, width, height) => {
+                // The capture thread paces frames, the pattern only has to keep up with the fastest setting
+                self.start_capture(pattern.name(), &key, move || TestPatternSource::new(pattern, width, height, MAX_FPS))
+            }
+        };
+        self.error_message = result.err().map(|error| error.to_string());
+    }
+
+    // List the displays again, and take new thumbnails if they changed or when forced.
+    // Displays being cast keep the thumbnail they have, a second capturer isn't opened on them.
+    fn refresh_displays(&mut self, force: bool) {
+        self.displays_checked = Some(Instant::now());
+        let displays = available_displays();
+        if !force && displays == self.displays {
+            return;
+        }
+        let cast: HashSet<&str> = self.sources.iter().map(|source| source.key.as_str()).collect();
+        self.thumbnails.retain(|id, _| cast.contains(id.as_str()));
+        let uncast: Vec<DisplayInfo> = displays.iter().filter(|display| !cast.contains(display.id.as_str())).cloned().collect();
+        self.displays = displays;
+        // Each one takes a moment, collected by collect_thumbnails as they arrive.
+        // A previous batch still running is dropped along with its receiver.
+        let (sender, receiver) = mpsc::channel();
+        self.pending_thumbnails = Some(receiver);
+        std::thread::spawn(move || {
+            for display in uncast {
+                let thumbnail = display_thumbnail(&display.id, THUMBNAIL_WIDTH);
+                if sender.send((display, thumbnail)).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    // Turn thumbnails taken in the background into textures
+    fn collect_thumbnails(&mut self, ctx: &egui::Context) {
+        let Some(receiver) = &self.pending_thumbnails else {
+            return;
+        };
+        loop {
+            match receiver.try_recv() {
+                Ok((display, Ok(frame))) => {
+                    let image = egui::ColorImage::from_rgba_unmultiplied([frame.width as usize, frame.height as usize], &frame.data);
+                    let texture = ctx.load_texture(format!("thumbnail_{}", display.id), image, Default::default());
+                    self.thumbnails.insert(display.id, texture);
+                }
+                Ok((display, Err(error))) => eprintln!("Failed to take a thumbnail of {}: {}", display.name, error),
+                Err(mpsc::TryRecvError::Empty) => {
+                    ctx.request_repaint_after(Duration::from_millis(50));
+                    return;
+                }
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.pending_thumbnails = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    // Monitors, windows and test patterns that can be shared. id_salt keeps the
+    // widgets apart when the picker is shown more than once.
+    fn render_source_picker(&mut self, ui: &mut egui::Ui, id_salt: &str) -> Option<SourceChoice> {
+        let mut choice = None;
+        ui.horizontal_wrapped(|ui| {
+            for display in &self.displays {
+                let button = match self.thumbnails.get(&display.id) {
+                    Some(texture) => egui::Button::image_and_text(
+                        egui::Image::new(texture).max_width(THUMBNAIL_WIDTH as f32),
+                        display.label(),
+                    ),
+                    None => egui::Button::new(display.label()),
+                };
+                if ui.add(button).clicked() {
+                    choice = Some(SourceChoice::Display(display.clone()));
+                }
+                ui.add_space(10.0);
+            }
+        });
+        if ui.button("Refresh Displays").clicked() {
+            self.refresh_displays(true);
+        }
+
+        // A single application window, covered parts included
+        ui.separator();
+        ui.label("Window");
+        ui.horizontal(|ui| {
+            let selected_title = self
+                .windows
+                .iter()
+                .find(|window| Some(window.id) == self.window)
+                .map_or("Pick a window", |window| window.title.as_str());
+            egui::ComboBox::from_id_source(("window", id_salt))
+                .selected_text(selected_title)
+                .width(240.0)
+                .show_ui(ui, |ui| {
+                    for window in &self.windows {
+                        ui.selectable_value(&mut self.window, Some(window.id), &window.title);
+                    }
+                });
+            if ui.button("Refresh").clicked() {
+                self.windows = available_windows();
+                if !self.windows.iter().any(|window| Some(window.id) == self.window) {
+                    self.window = None;
+                }
+            }
+        });
+        let window = self.windows.iter().find(|window| Some(window.id) == self.window).cloned();
+        if ui.add_enabled(window.is_some(), egui::Button::new("Share Window")).clicked() {
+            choice = window.map(SourceChoice::Window);
         }
+
+        // Synthetic source, for machines without a monitor
+        ui.separator();
+        ui.label("Test Pattern");
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source(("pattern", id_salt))
+                .selected_text(self.pattern.name())
+                .show_ui(ui, |ui| {
+                    for pattern in Pattern::ALL {
+                        ui.selectable_value(&mut self.pattern, pattern, pattern.name());
+                    }
+                });
+            egui::ComboBox::from_id_source(("pattern_size", id_salt))
+                .selected_text(format!("{}x{}", self.pattern_size.0, self.pattern_size.1))
+                .show_ui(ui, |ui| {
+                    for size in PATTERN_SIZES {
+                        ui.selectable_value(&mut self.pattern_size, size, format!("{}x{}", size.0, size.1));
+                    }
+                });
+        });
+        if ui.button("Cast Test Pattern").clicked() {
+            let (width, height) = self.pattern_size;
+            choice = Some(SourceChoice::Pattern(self.pattern, width, height));
+        }
+        choice
+    }
+
+    // Listen address controls and the server status
+    fn render_server_settings(&mut self, ui: &mut egui::Ui) {
+        match &self.server_status {
+            Ok(addr) => ui.label(format!("Listening on {}", addr)),
+            Err(error) => ui.colored_label(egui::Color32::RED, error),
+        };
+
+        ui.collapsing("Server Settings", |ui| {
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.server_config.ip_version, IpVersion::V4, IpVersion::V4.name());
+                ui.radio_value(&mut self.server_config.ip_version, IpVersion::V6, IpVersion::V6.name());
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.server_config.address)
+                        .hint_text("All interfaces")
+                        .desired_width(160.0),
+                );
+                ui.label("Port");
+                ui.add(egui::DragValue::new(&mut self.server_config.port).range(1..=65535));
+                if ui.button("Restart Server").clicked() {
+                    self.server_status = self.server.listen(&self.server_config);
+                }
+            });
+        });
+    }
+
+    // What the capture thread of a source should apply, from the current UI state
+    fn cast_settings(&self, key: &str, crop: &CropValues) -> CastSettings {
+        CastSettings {
+            crop: crop.clone(),
+            trim_crop: self.trim_crop,
+            resolution: self.resolution,
+            masks: self.masks.get(key).cloned().unwrap_or_default(),
+            cursor_mode: self.cursor_mode,
+            is_streaming: self.is_streaming,
+            is_blank: self.is_blank,
+        }
+    }
+
+    // Hand the settings to every capture thread. Only the UI changes them,
+    // so once per render keeps the threads up to date.
+    fn sync_settings(&self) {
+        for source in &self.sources {
+            *source.settings.lock().unwrap() = self.cast_settings(&source.key, &source.crop);
+        }
+    }
+
+    // Write the masks once an edit is finished, rather than on every keystroke or
+    // frame of a drag. Masks under unstable keys are left out.
+    fn save_finished_masks(&mut self, ctx: &egui::Context) {
+        let editing = ctx.is_using_pointer() || ctx.memory(|memory| memory.focused().is_some());
+        if !self.masks_changed || editing {
+            return;
+        }
+        self.masks_changed = false;
+        let persisted: SavedMasks = self
+            .masks
+            .iter()
+            .filter(|(key, _)| !self.unsaved_masks.contains(*key))
+            .map(|(key, regions)| (key.clone(), regions.clone()))
+            .collect();
+        self.error_message = save_masks(&persisted).err();
     }
 
     // Render method for the Caster mode
     pub fn render(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+        self.render_controls(ui, ctx);
+        self.sync_settings();
+        self.save_finished_masks(ctx);
+    }
+
+    fn render_controls(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
         ui.heading("Caster Mode");
+        self.render_server_settings(ui);
         ui.add_space(20.0);
-        // Try to receive a frame from the capture thread
-        if let Some(capture) = &mut self.capture {
-            if let Some(frame) = capture.receive_frame() {
-                self.current_frame = Some(frame.clone());
-                crop(&mut self.current_frame.as_mut().unwrap(), self.crop.clone());
-                blank(&mut self.current_frame.as_mut().unwrap(), self.is_blank);
-                self.server.broadcast_frame(self.current_frame.clone().unwrap(), self.is_streaming);
+        // Every source is captured and streamed, whichever one is being previewed
+        for source in &mut self.sources {
+            if source.error.is_none() {
+                if let Some(error) = source.capture.error() {
+                    eprintln!("Capture of {} stopped: {}", source.channel, error);
+                    source.error = Some(error.to_string());
+                }
+            }
+            // Processed and sent on the capture thread, only the preview is left to show
+            if let Some(frame) = source.capture.receive_frame() {
+                source.current_frame = Some(frame);
             }
         }
-        // display possible screens to capture
-        else {
-            for (index, name) in self.displays.iter().enumerate() {
-                if ui.add(egui::Button::new(name)).clicked() {
-                     self.capture = Some(ScreenCapture::new(index).unwrap());
-                }
-                ui.add_space(10.0);
+
+        if let Some(error) = &self.error_message {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+
+        // Pick up monitors that were plugged in or out
+        if self.displays_checked.is_none_or(|checked| checked.elapsed() >= DISPLAY_POLL_INTERVAL) {
+            self.refresh_displays(false);
+        }
+        self.collect_thumbnails(ctx);
+
+        // display possible sources to capture
+        if self.sources.is_empty() {
+            ctx.request_repaint_after(DISPLAY_POLL_INTERVAL);
+            if let Some(choice) = self.render_source_picker(ui, "start") {
+                self.start_choice(choice);
+            }
+            return;
+        }
+        // Come back in time for the next captured frame
+        ctx.request_repaint_after(Duration::from_secs(1) / self.fps);
+
+        // One tab per channel
+        let mut stop = None;
+        ui.horizontal(|ui| {
+            for (index, source) in self.sources.iter().enumerate() {
+                ui.selectable_value(&mut self.selected, index, &source.channel);
             }
+            if ui.button("Stop").on_hover_text("Stop sharing the selected source").clicked() {
+                stop = Some(self.selected);
+            }
+        });
+        if let Some(index) = stop {
+            self.stop_capture(index);
+            return;
+        }
+        let added = ui.collapsing("Add Source", |ui| self.render_source_picker(ui, "add")).body_returned.flatten();
+        if let Some(choice) = added {
+            self.start_choice(choice);
         }
+        let switched = ui
+            .collapsing("Switch Source", |ui| {
+                ui.label("Receivers of this channel stay connected and see the new source");
+                self.render_source_picker(ui, "switch")
+            })
+            .body_returned
+            .flatten();
+        if let Some(choice) = switched {
+            self.switch_capture(self.selected, choice);
+        }
+        ui.add_space(10.0);
+
         // Display the captured frame (if available)
-        if let Some(frame) = &self.current_frame {
-            ui.columns(4, |columns| {
+        let source = &mut self.sources[self.selected];
+        if let Some(error) = &source.error {
+            ui.colored_label(egui::Color32::RED, format!("{}: {}", source.channel, error));
+        }
+        if let Some(frame) = &source.current_frame {
+            let previous_quality = self.quality;
+            ui.columns(5, |columns| {
                 let slider_width = columns[0].available_width() / 1.0; // Width of each slider (columns width)
             
                 // Left Crop Slider
@@ -61,7 +509,7 @@ impl Caster {
                     ui.label("Left");
                     ui.add_sized(
                         [slider_width, 20.0],
-                        egui::Slider::new(&mut self.crop.left, 0.0..=100.0),
+                        egui::Slider::new(&mut source.crop.left, 0.0..=100.0),
                     );
                 });
             
@@ -70,7 +518,7 @@ impl Caster {
                     ui.label("Right");
                     ui.add_sized(
                         [slider_width, 20.0],
-                        egui::Slider::new(&mut self.crop.right, 0.0..=100.0),
+                        egui::Slider::new(&mut source.crop.right, 0.0..=100.0),
                     );
                 });
             
@@ -79,7 +527,7 @@ impl Caster {
                     ui.label("Top");
                     ui.add_sized(
                         [slider_width, 20.0],
-                        egui::Slider::new(&mut self.crop.top, 0.0..=100.0),
+                        egui::Slider::new(&mut source.crop.top, 0.0..=100.0),
                     );
                 });
             
@@ -88,10 +536,99 @@ impl Caster {
                     ui.label("Bottom");
                     ui.add_sized(
                         [slider_width, 20.0],
-                        egui::Slider::new(&mut self.crop.bottom, 0.0..=100.0),
+                        egui::Slider::new(&mut source.crop.bottom, 0.0..=100.0),
                     );
                 });
+
+                // Quality Slider, only meaningful for the lossy codecs
+                columns[4].vertical(|ui| {
+                    ui.label("Quality");
+                    ui.add_enabled_ui(self.codec.is_lossy(), |ui| {
+                        ui.add_sized(
+                            [slider_width, 20.0],
+                            egui::Slider::new(&mut self.quality, 1..=100),
+                        );
+                    });
+                });
+            });
+            if self.quality != previous_quality {
+                self.server.set_quality(self.quality);
+            }
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.trim_crop, "Send only the cropped area");
+                ui.label("Aspect");
+                let previous_aspect = source.crop_selector.aspect;
+                egui::ComboBox::from_id_source("crop_aspect")
+                    .selected_text(source.crop_selector.aspect.name())
+                    .show_ui(ui, |ui| {
+                        for aspect in AspectLock::ALL {
+                            ui.selectable_value(&mut source.crop_selector.aspect, aspect, aspect.name());
+                        }
+                    });
+                if source.crop_selector.aspect != previous_aspect {
+                    source.crop_selector.apply_aspect(&mut source.crop, frame.width, frame.height);
+                }
+                if ui.button("Reset Crop").clicked() {
+                    source.crop = CropValues::new(0.0, 0.0, 0.0, 0.0);
+                    source.crop_selector.apply_aspect(&mut source.crop, frame.width, frame.height);
+                }
+            });
+
+            let saved = self.masks.get(&source.key).cloned().unwrap_or_default();
+            let mut regions = saved.clone();
+            ui.collapsing("Privacy Masks", |ui| {
+                let mut remove = None;
+                for (index, region) in regions.iter_mut().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.add(egui::TextEdit::singleline(&mut region.name).desired_width(120.0));
+                        egui::ComboBox::from_id_source(("mask_style", index))
+                            .selected_text(region.style.name())
+                            .show_ui(ui, |ui| {
+                                for style in MaskStyle::ALL {
+                                    ui.selectable_value(&mut region.style, style, style.name());
+                                }
+                            });
+                        let area = &mut region.area;
+                        for (label, value) in [("Left", &mut area.left), ("Right", &mut area.right), ("Top", &mut area.top), ("Bottom", &mut area.bottom)] {
+                            ui.label(label);
+                            ui.add(egui::DragValue::new(value).range(0.0..=100.0).speed(0.2).suffix("%"));
+                        }
+                        if ui.button("Remove").clicked() {
+                            remove = Some(index);
+                        }
+                    });
+                }
+                if let Some(index) = remove {
+                    regions.remove(index);
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("Add Mask").clicked() {
+                        regions.push(MaskRegion {
+                            name: format!("Mask {}", regions.len() + 1),
+                            area: CropValues::new(25.0, 25.0, 25.0, 25.0),
+                            style: MaskStyle::Blur,
+                        });
+                    }
+                    // Drag a selection on the preview, then turn it into a mask
+                    if ui.button("Mask Selection").on_hover_text("Mask the crop selection and reset the crop").clicked() {
+                        regions.push(MaskRegion {
+                            name: format!("Mask {}", regions.len() + 1),
+                            area: source.crop.clone(),
+                            style: MaskStyle::Blur,
+                        });
+                        source.crop = CropValues::new(0.0, 0.0, 0.0, 0.0);
+                    }
+                });
             });
+            // Applied right away, the capture thread starts the channel over if the masked area changed
+            if regions != saved {
+                if regions.is_empty() {
+                    self.masks.remove(&source.key);
+                } else {
+                    self.masks.insert(source.key.clone(), regions);
+                }
+                self.masks_changed = true;
+            }
             ui.add_space(20.0);
 
             let width = frame.width as usize;
@@ -117,13 +654,95 @@ impl Caster {
                 egui::vec2(available_size.x, available_size.x / aspect_ratio)
             };
 
-            // Display the image
-            ui.add(egui::Image::new(&image_handle).fit_to_exact_size(target_size));
+            // Display the image, with the crop selection drawn on top
+            source.crop_selector.show(ui, &image_handle, target_size, frame.width, frame.height, &mut source.crop);
 
             ui.add_space(10.0);
 
             let client_count = self.server.get_client_count();
             ui.label(format!("Connected Clients: {}", client_count));
+            if client_count > 0 {
+                ui.collapsing("Client Details", |ui| {
+                    for (addr, stats) in self.server.client_stats() {
+                        ui.label(format!(
+                            "{} on {}: {} quality, {} kbps, {} queued, {:.1} MB sent, {} skipped, {} resyncs",
+                            addr,
+                            stats.channel,
+                            stats.tier.name(),
+                            stats.throughput_kbps,
+                            stats.queue_depth,
+                            stats.bytes_sent as f64 / 1_000_000.0,
+                            stats.skipped,
+                            stats.resyncs
+                        ));
+                    }
+                });
+            }
+
+            ui.horizontal(|ui| {
+                let previous_fps = self.fps;
+                ui.add(egui::Slider::new(&mut self.fps, 1..=MAX_FPS).text("FPS"));
+                if self.fps != previous_fps {
+                    for source in &self.sources {
+                        source.capture.set_fps(self.fps);
+                    }
+                    // The H.264 rate control spreads the bitrate over this many frames a second
+                    self.h264_settings.frame_rate = self.fps;
+                    self.server.set_h264_settings(self.h264_settings);
+                }
+
+                ui.label("Resolution");
+                let is_custom = matches!(self.resolution, Resolution::Custom { .. });
+                egui::ComboBox::from_id_source("resolution")
+                    .selected_text(self.resolution.name())
+                    .show_ui(ui, |ui| {
+                        for resolution in Resolution::PRESETS {
+                            ui.selectable_value(&mut self.resolution, resolution, resolution.name());
+                        }
+                        if ui.selectable_label(is_custom, "Custom").clicked() && !is_custom {
+                            self.resolution = Resolution::Custom { width: 1280, height: 720 };
+                        }
+                    });
+                if let Resolution::Custom { width, height } = &mut self.resolution {
+                    ui.add(egui::DragValue::new(width).range(16..=MAX_DIMENSION));
+                    ui.label("x");
+                    ui.add(egui::DragValue::new(height).range(16..=MAX_DIMENSION));
+                }
+
+                ui.label("Pointer");
+                egui::ComboBox::from_id_source("cursor_mode")
+                    .selected_text(self.cursor_mode.name())
+                    .show_ui(ui, |ui| {
+                        for mode in CursorMode::ALL {
+                            ui.selectable_value(&mut self.cursor_mode, mode, mode.name());
+                        }
+                    });
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Compression");
+                let previous_codec = self.codec;
+                egui::ComboBox::from_id_source("codec")
+                    .selected_text(self.codec.name())
+                    .show_ui(ui, |ui| {
+                        for codec in Codec::available() {
+                            ui.selectable_value(&mut self.codec, codec, codec.name());
+                        }
+                    });
+                if self.codec != previous_codec {
+                    self.server.set_codec(self.codec);
+                }
+
+                if self.codec.is_video() {
+                    let previous_settings = self.h264_settings;
+                    ui.add(egui::DragValue::new(&mut self.h264_settings.bitrate_kbps).range(100..=50_000).suffix(" kbps"));
+                    ui.label("GOP");
+                    ui.add(egui::DragValue::new(&mut self.h264_settings.gop_length).range(1..=600).suffix(" frames"));
+                    if self.h264_settings != previous_settings {
+                        self.server.set_h264_settings(self.h264_settings);
+                    }
+                }
+            });
     
             ui.add_space(10.0);
     
@@ -151,4 +770,4 @@ impl Caster {
             });
         }
     }
-}
\ No newline at end of file
+}
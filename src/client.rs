@@ -1,11 +1,24 @@
-use tokio::net::TcpStream;
-use tokio::io::{self,AsyncReadExt};
+use tokio::net::{lookup_host, TcpStream};
 use tokio::sync::{mpsc,watch};
 use std::net::SocketAddr;
-use bincode;
 use crate::screen::Frame;
+use crate::codec::FrameDecoder;
+use crate::cursor::CursorShape;
+use crate::protocol::{self, CursorState, Hello, Message, ProtocolError, StreamMetadata, DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_PORT, PING_INTERVAL};
 use tokio::time::{timeout, Duration};
 
+// What the receiver task reports to the UI
+pub enum ClientEvent {
+    Frame(Frame),
+    Paused,
+    Blanked,
+    Metadata(StreamMetadata),
+    Channels(Vec<String>), // Streams the caster offers, sent once after connecting
+    Cursor(CursorState),
+    CursorShape(CursorShape),
+    Disconnected(Option<String>), // None when the caster ended the session normally
+}
+
 #[derive(Clone)]
 pub struct DisconnectHandle {
     shutdown_tx: watch::Sender<bool>,
@@ -18,89 +31,174 @@ impl DisconnectHandle {
     }
 }
 
-// The function to connect to the server and start receiving frames
-pub async fn connect_to_server(
-    ip_address: &str,
-) -> Result<(mpsc::Receiver<Option<Frame>>, DisconnectHandle), String > {
-    let port = 9041;
-    let address_port = format!("{}:{}", ip_address, port);
-
-    let addr: SocketAddr = address_port.parse().map_err(|_| {
-        format!("Invalid IP address format: {}", ip_address)
-    })?;
+// Split "host", "host:port", "[ipv6]" or "[ipv6]:port" into host and port.
+// A bare IPv6 address has several colons, so it is taken whole with the default port.
+fn split_host_port(address: &str) -> Result<(&str, u16), String> {
+    let address = address.trim();
+    if address.is_empty() {
+        return Err("Enter an address to connect to".to_string());
+    }
+    let parse_port = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|_| format!("Invalid port \"{}\" in {}", port, address))
+    };
+    if let Some(rest) = address.strip_prefix('[') {
+        let (host, after) = rest
+            .split_once(']')
+            .ok_or_else(|| format!("Missing closing bracket in {}", address))?;
+        return match after {
+            "" => Ok((host, DEFAULT_PORT)),
+            _ => match after.strip_prefix(':') {
+                Some(port) => Ok((host, parse_port(port)?)),
+                None => Err(format!("Invalid address format: {}", address)),
+            },
+        };
+    }
+    match address.split_once(':') {
+        Some((host, port)) if !port.contains(':') => Ok((host, parse_port(port)?)),
+        _ => Ok((address, DEFAULT_PORT)),
+    }
+}
 
-    // Attempt to connect to the server
-    let mut stream = timeout(Duration::from_secs(10), TcpStream::connect(addr))
+// Connect to the first resolved address that accepts, and complete the handshake on it.
+// Returns the stream along with the caster's hello.
+async fn connect_any(address: &str, hello: &Hello) -> Result<(TcpStream, Hello), String> {
+    let (host, port) = split_host_port(address)?;
+    let addrs: Vec<SocketAddr> = timeout(Duration::from_secs(10), lookup_host((host, port)))
         .await
-        .map_err(|_| format!("Connection to {}:{} timed out", ip_address, port))?
-        .map_err(|_| format!("Connection to {}:{} failed", ip_address, port))?;
+        .map_err(|_| format!("Looking up {} timed out", host))?
+        .map_err(|e| format!("Could not resolve {}: {}", host, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("{} did not resolve to any address", host));
+    }
+
+    let mut errors = Vec::new();
+    for addr in addrs {
+        // Attempt to connect to the server
+        let mut stream = match timeout(Duration::from_secs(10), TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                errors.push(format!("connection to {} failed: {}", addr, e));
+                continue;
+            }
+            Err(_) => {
+                errors.push(format!("connection to {} timed out", addr));
+                continue;
+            }
+        };
+
+        // Make sure both ends speak the same protocol before streaming.
+        // A wrong peer won't get better on another address, so stop here.
+        let caster_hello = timeout(Duration::from_secs(10), protocol::handshake(&mut stream, hello))
+            .await
+            .map_err(|_| format!("Handshake with {} timed out", addr))?
+            .map_err(|e| format!("Handshake with {} failed: {}", addr, e))?;
+
+        println!("Successfully connected to {}", addr);
+        return Ok((stream, caster_hello));
+    }
+    Err(format!("Could not connect to {}: {}", address.trim(), errors.join("; ")))
+}
 
-    println!("Successfully connected to {}", addr);
+// The function to connect to the server and start receiving frames.
+// channel picks one of the caster's streams, None watches its first.
+pub async fn connect_to_server(
+    address: &str,
+    channel: Option<String>,
+) -> Result<(mpsc::Receiver<ClientEvent>, DisconnectHandle), String > {
+    let hello = Hello { channel, ..Hello::local() };
+    let (mut stream, caster_hello) = connect_any(address, &hello).await?;
 
     // Create an MPSC channel to send frames from the receiver task
-    let (frame_tx, frame_rx) = mpsc::channel(10);
+    let (event_tx, event_rx) = mpsc::channel(10);
 
     // Create a watch channel for shutdown signaling
     let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
     // Spawn a task to handle receiving data from the server
     tokio::spawn(async move {
+        let mut decoder = FrameDecoder::new(hello.max_frame_pixels);
+        let mut closed_reason = None;
+        let _ = event_tx.send(ClientEvent::Channels(caster_hello.channels)).await;
         loop {
             // Check for shutdown signal
             if *shutdown_rx.borrow() {
                 break;
             }
 
-            let mut size_buffer = [0u8; 4];
-            match stream.read_exact(&mut size_buffer).await {
-                Ok(_) => {
-                    let frame_size = u32::from_be_bytes(size_buffer) as usize;
-                    if frame_size == 0 {
-                        continue;
+            // A dropped network often leaves the connection open with nothing arriving,
+            // the caster's pings tell that apart from a quiet stream
+            let message = protocol::read_message(&mut stream, DEFAULT_MAX_MESSAGE_SIZE, 3 * PING_INTERVAL).await;
+            let event = match message {
+                Ok(Message::Frame(encoded)) => match decoder.decode(encoded) {
+                    Ok(Some(frame)) if frame.is_valid() => ClientEvent::Frame(frame),
+                    Ok(Some(frame)) => {
+                        let reason = format!("Frame data does not match its {}x{} size", frame.width, frame.height);
+                        eprintln!("{}", reason);
+                        closed_reason = Some(Some(reason));
+                        break;
                     }
-
-                    // Step 2: Read the frame data
-                    let mut frame_buffer = vec![0u8; frame_size];
-                    match stream.read_exact(&mut frame_buffer).await {
-                        Ok(_) => {
-                            match bincode::deserialize::<Frame>(&frame_buffer) {
-                                Ok(frame) => {
-                                    // Step 4: Send the frame to the main application via the channel
-                                    if frame_tx.send(Some(frame)).await.is_err() {
-                                        // If the receiver side is closed, stop the loop
-                                        break;
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("Failed to deserialize frame: {}", e);
-                                    break;
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to read frame data: {}", e);
-                            // Handle EOF or other read errors
-                            if e.kind() == io::ErrorKind::UnexpectedEof {
-                                println!("Connection closed by server.");
-                                if let Err(_e) = frame_tx.send(None).await {
-                                    eprintln!("Failed to notify receiver about connection closure");
-                                }
-                            }
-                            break;
-                        }
+                    Ok(None) => continue, // Waiting for a keyframe
+                    Err(e) => {
+                        eprintln!("Failed to decode frame: {}", e);
+                        closed_reason = Some(Some(format!("Failed to decode frame: {}", e)));
+                        break;
+                    }
+                },
+                Ok(Message::Paused) => ClientEvent::Paused,
+                Ok(Message::Blanked) => ClientEvent::Blanked,
+                Ok(Message::Metadata(metadata)) => ClientEvent::Metadata(metadata),
+                // Both go straight to drawing code that trusts them
+                Ok(Message::Cursor(state)) => match state.check() {
+                    Ok(()) => ClientEvent::Cursor(state),
+                    Err(reason) => {
+                        eprintln!("{}", reason);
+                        closed_reason = Some(Some(reason));
+                        break;
+                    }
+                },
+                Ok(Message::CursorShape(shape)) => match shape.check() {
+                    Ok(()) => ClientEvent::CursorShape(shape),
+                    Err(reason) => {
+                        eprintln!("{}", reason);
+                        closed_reason = Some(Some(reason));
+                        break;
                     }
+                },
+                Ok(Message::Ping) => continue,
+                Ok(Message::Goodbye(reason)) => {
+                    println!("Server said goodbye: {}", reason);
+                    closed_reason = Some(Some(reason));
+                    break;
+                }
+                Err(ProtocolError::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    eprintln!("Nothing received from the caster for {:?}", 3 * PING_INTERVAL);
+                    closed_reason = Some(Some("Connection timed out".to_string()));
+                    break;
+                }
+                Err(ProtocolError::Closed) => {
+                    println!("Connection closed by server.");
+                    closed_reason = Some(None);
+                    break;
                 }
                 Err(e) => {
-                    eprintln!("Failed to read frame size: {}", e);
-                    // Handle EOF or other read errors
-                    if e.kind() == io::ErrorKind::UnexpectedEof {
-                        println!("Connection closed by server.");
-                        if let Err(_e) = frame_tx.send(None).await {
-                            eprintln!("Failed to notify receiver about connection closure");
-                        }
-                    }
+                    eprintln!("Failed to read message: {}", e);
+                    closed_reason = Some(Some(e.to_string()));
                     break;
                 }
+            };
+
+            // Send the event to the main application via the channel
+            if event_tx.send(event).await.is_err() {
+                // If the receiver side is closed, stop the loop
+                break;
+            }
+        }
+        // Tell the UI why the stream ended, unless it asked us to stop
+        if let Some(reason) = closed_reason {
+            if event_tx.send(ClientEvent::Disconnected(reason)).await.is_err() {
+                eprintln!("Failed to notify receiver about connection closure");
             }
         }
         if let Ok(stream_std) = stream.into_std() {
@@ -113,7 +211,43 @@ pub async fn connect_to_server(
         println!("Receiver task exiting.");
     });
 
-    // Return the frame receiver and disconnect handle to the caller
+    // Return the event receiver and disconnect handle to the caller
     let disconnect_handle = DisconnectHandle { shutdown_tx };
-    Ok((frame_rx, disconnect_handle))
-}
\ No newline at end of file
+    Ok((event_rx, disconnect_handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_without_port_gets_the_default() {
+        assert_eq!(split_host_port("caster.local"), Ok(("caster.local", DEFAULT_PORT)));
+        assert_eq!(split_host_port("  192.168.1.5 "), Ok(("192.168.1.5", DEFAULT_PORT)));
+    }
+
+    #[test]
+    fn host_with_port() {
+        assert_eq!(split_host_port("192.168.1.5:9000"), Ok(("192.168.1.5", 9000)));
+        assert_eq!(split_host_port("caster.local:1"), Ok(("caster.local", 1)));
+    }
+
+    #[test]
+    fn bracketed_ipv6() {
+        assert_eq!(split_host_port("[::1]:9041"), Ok(("::1", 9041)));
+        assert_eq!(split_host_port("[fe80::1]"), Ok(("fe80::1", DEFAULT_PORT)));
+    }
+
+    #[test]
+    fn bare_ipv6_is_taken_whole() {
+        assert_eq!(split_host_port("::1"), Ok(("::1", DEFAULT_PORT)));
+        assert_eq!(split_host_port("fe80::1:2"), Ok(("fe80::1:2", DEFAULT_PORT)));
+    }
+
+    #[test]
+    fn bad_addresses_are_rejected() {
+        for address in ["", "   ", "host:", "host:port", "host:65536", "host:-1", "[::1]:x", "[::1", "[::1]9041"] {
+            assert!(split_host_port(address).is_err(), "{:?}", address);
+        }
+    }
+}
@@ -0,0 +1,513 @@
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use image::{DynamicImage, ExtendedColorType, ImageDecoder};
+use image::codecs::jpeg::{JpegDecoder, JpegEncoder};
+use crate::screen::{Frame, DEFAULT_FPS};
+#[cfg(feature = "h264")]
+use crate::h264::{H264Decoder, H264Encoder};
+
+pub const TILE_SIZE: u32 = 64;
+// Lossy codecs carry a header per tile, so they work on bigger tiles
+pub const LOSSY_TILE_SIZE: u32 = 256;
+pub const DEFAULT_QUALITY: u8 = 75;
+// Largest frame side a receiver accepts, so a bogus header can't trigger a huge allocation
+pub const MAX_DIMENSION: u32 = 16384;
+// A full keyframe is sent at least this often, so receivers recover from any lost state
+pub const KEYFRAME_INTERVAL: u64 = 100;
+
+const ZSTD_LEVEL: i32 = 3;
+
+// How tile pixels are compressed on the wire, chosen by the caster
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Codec {
+    Raw,
+    Zstd,
+    Lz4,
+    Qoi,
+    Jpeg,
+    WebP,
+    H264, // A single whole-frame tile holding an H.264 access unit
+}
+
+impl Codec {
+    pub const ALL: [Codec; 7] = [Codec::Raw, Codec::Zstd, Codec::Lz4, Codec::Qoi, Codec::Jpeg, Codec::WebP, Codec::H264];
+
+    // Codecs this build can encode and decode
+    pub fn available() -> impl Iterator<Item = Codec> {
+        Self::ALL.into_iter().filter(|codec| *codec != Codec::H264 || cfg!(feature = "h264"))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Raw => "None",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "LZ4",
+            Codec::Qoi => "QOI",
+            Codec::Jpeg => "JPEG",
+            Codec::WebP => "WebP",
+            Codec::H264 => "H.264",
+        }
+    }
+
+    pub fn is_lossy(&self) -> bool {
+        matches!(self, Codec::Jpeg | Codec::WebP | Codec::H264)
+    }
+
+    // Inter-frame codecs keep their own reference state and only accept whole frames
+    pub fn is_video(&self) -> bool {
+        *self == Codec::H264
+    }
+
+    fn tile_size(&self) -> u32 {
+        if self.is_lossy() { LOSSY_TILE_SIZE } else { TILE_SIZE }
+    }
+}
+
+// Rate control for the H.264 path
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct H264Settings {
+    pub bitrate_kbps: u32,
+    pub gop_length: u32, // Frames between keyframes
+    pub frame_rate: u32, // The capture rate, which the bitrate is spread over
+}
+
+impl Default for H264Settings {
+    fn default() -> Self {
+        Self { bitrate_kbps: 4000, gop_length: 120, frame_rate: DEFAULT_FPS }
+    }
+}
+
+// A rectangle of pixels, compressed with the frame's codec, to be copied into the receiver's image
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+// What goes on the wire for one frame: either every tile (keyframe) or only
+// the tiles that changed since the previous frame
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EncodedFrame {
+    pub seq: u64,
+    pub width: u32,
+    pub height: u32,
+    pub keyframe: bool,
+    pub codec: Codec,
+    pub tiles: Vec<Tile>,
+}
+
+pub struct FrameEncoder {
+    codec: Codec,
+    quality: u8, // 1-100, only used by lossy codecs
+    previous: Option<Frame>,
+    seq: u64,
+    last_keyframe: u64,
+    force_keyframe: bool,
+    h264_settings: H264Settings,
+    #[cfg(feature = "h264")]
+    h264: Option<H264Encoder>,
+}
+
+impl FrameEncoder {
+    pub fn new() -> Self {
+        Self {
+            codec: Codec::Raw,
+            quality: DEFAULT_QUALITY,
+            previous: None,
+            seq: 0,
+            last_keyframe: 0,
+            force_keyframe: true,
+            h264_settings: H264Settings::default(),
+            #[cfg(feature = "h264")]
+            h264: None,
+        }
+    }
+
+    // Make the next encoded frame a keyframe
+    pub fn request_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    pub fn set_codec(&mut self, codec: Codec) {
+        // Receivers can't continue a video stream from tiles or vice versa
+        if codec != self.codec && (codec.is_video() || self.codec.is_video()) {
+            self.force_keyframe = true;
+        }
+        self.codec = codec;
+    }
+
+    pub fn set_h264_settings(&mut self, settings: H264Settings) {
+        self.h264_settings = settings;
+    }
+
+    pub fn set_quality(&mut self, quality: u8) {
+        self.quality = quality.clamp(1, 100);
+    }
+
+    // Whether the frame is identical to the last one encoded, so there is nothing to send
+    pub fn is_unchanged(&self, frame: &Frame) -> bool {
+        self.previous.as_ref().is_some_and(|previous| {
+            previous.width == frame.width && previous.height == frame.height && previous.data == frame.data
+        })
+    }
+
+    pub fn encode(&mut self, frame: &Frame) -> Result<EncodedFrame, String> {
+        self.seq += 1;
+        let size_changed = self
+            .previous
+            .as_ref()
+            .is_none_or(|previous| previous.width != frame.width || previous.height != frame.height);
+        let interval = if self.codec.is_video() { self.h264_settings.gop_length.max(1) as u64 } else { KEYFRAME_INTERVAL };
+        let keyframe = self.force_keyframe || size_changed || self.seq - self.last_keyframe >= interval;
+
+        let (tiles, width, height) = if self.codec.is_video() {
+            let tile = self.encode_video(frame, keyframe)?;
+            let (width, height) = (tile.width, tile.height);
+            (vec![tile], width, height)
+        } else {
+            (self.encode_tiles(frame, keyframe)?, frame.width, frame.height)
+        };
+
+        if keyframe {
+            self.last_keyframe = self.seq;
+            self.force_keyframe = false;
+        }
+        self.previous = Some(frame.clone());
+
+        Ok(EncodedFrame {
+            seq: self.seq,
+            width,
+            height,
+            keyframe,
+            codec: self.codec,
+            tiles,
+        })
+    }
+
+    fn encode_tiles(&self, frame: &Frame, keyframe: bool) -> Result<Vec<Tile>, String> {
+        let mut tiles = Vec::new();
+        for (x, y, width, height) in tile_grid(frame.width, frame.height, self.codec.tile_size()) {
+            let changed = keyframe || self.previous.as_ref().is_some_and(|previous| tile_differs(previous, frame, x, y, width, height));
+            if changed {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width,
+                    height,
+                    data: compress(self.codec, self.quality, &copy_tile(frame, x, y, width, height), width, height)?,
+                });
+            }
+        }
+        Ok(tiles)
+    }
+
+    // H.264 works on the whole frame, trimmed to even dimensions
+    #[cfg(feature = "h264")]
+    fn encode_video(&mut self, frame: &Frame, keyframe: bool) -> Result<Tile, String> {
+        let width = frame.width & !1;
+        let height = frame.height & !1;
+        if width == 0 || height == 0 {
+            return Err("Frame is too small for H.264".to_string());
+        }
+        let reusable = self.h264.as_ref().is_some_and(|encoder| {
+            encoder.width == width && encoder.height == height && encoder.settings == self.h264_settings
+        });
+        if !reusable || keyframe {
+            self.h264 = Some(H264Encoder::new(width, height, self.h264_settings)?);
+        }
+        let data = self.h264.as_mut().unwrap().encode(&copy_tile(frame, 0, 0, width, height), keyframe)?;
+        Ok(Tile { x: 0, y: 0, width, height, data })
+    }
+
+    #[cfg(not(feature = "h264"))]
+    fn encode_video(&mut self, _frame: &Frame, _keyframe: bool) -> Result<Tile, String> {
+        Err("H.264 support is not compiled in".to_string())
+    }
+}
+
+// Rebuilds full frames on the receiving side
+pub struct FrameDecoder {
+    current: Option<Frame>,
+    max_pixels: u64, // Frames larger than this are rejected before allocating anything
+    last_seq: u64,
+    #[cfg(feature = "h264")]
+    h264: Option<H264Decoder>,
+}
+
+impl FrameDecoder {
+    pub fn new(max_pixels: u64) -> Self {
+        Self {
+            current: None,
+            max_pixels,
+            last_seq: 0,
+            #[cfg(feature = "h264")]
+            h264: None,
+        }
+    }
+
+    // Apply an encoded frame. Returns None while waiting for a keyframe after
+    // joining mid-stream or missing a delta.
+    pub fn decode(&mut self, encoded: EncodedFrame) -> Result<Option<Frame>, String> {
+        let valid_size = (1..=MAX_DIMENSION).contains(&encoded.width) && (1..=MAX_DIMENSION).contains(&encoded.height);
+        if !valid_size {
+            return Err(format!("Invalid frame size {}x{}", encoded.width, encoded.height));
+        }
+        let pixels = encoded.width as u64 * encoded.height as u64;
+        if pixels > self.max_pixels {
+            return Err(format!(
+                "Frame size {}x{} exceeds the limit of {} pixels",
+                encoded.width, encoded.height, self.max_pixels
+            ));
+        }
+        if encoded.keyframe {
+            // Reuse the buffer when the size is unchanged instead of allocating for every keyframe
+            match &mut self.current {
+                Some(current) if current.width == encoded.width && current.height == encoded.height => current.data.fill(0),
+                _ => {
+                    self.current = Some(Frame {
+                        data: vec![0; pixels as usize * 4],
+                        width: encoded.width,
+                        height: encoded.height,
+                    });
+                }
+            }
+        } else {
+            let in_sequence = self.current.as_ref().is_some_and(|current| {
+                current.width == encoded.width && current.height == encoded.height && encoded.seq == self.last_seq + 1
+            });
+            if !in_sequence {
+                self.current = None;
+                return Ok(None);
+            }
+        }
+        self.last_seq = encoded.seq;
+
+        for tile in &encoded.tiles {
+            let current = self.current.as_ref().unwrap();
+            check_tile_bounds(current, tile)?;
+            let pixels = if encoded.codec.is_video() {
+                match self.decode_video(tile, encoded.keyframe)? {
+                    Some(pixels) => pixels,
+                    None => continue,
+                }
+            } else {
+                decompress(encoded.codec, tile)?
+            };
+            paste_tile(self.current.as_mut().unwrap(), tile, &pixels);
+        }
+        Ok(self.current.clone())
+    }
+
+    #[cfg(feature = "h264")]
+    fn decode_video(&mut self, tile: &Tile, keyframe: bool) -> Result<Option<Vec<u8>>, String> {
+        if keyframe || self.h264.is_none() {
+            self.h264 = Some(H264Decoder::new()?);
+        }
+        self.h264.as_mut().unwrap().decode(&tile.data, tile.width, tile.height)
+    }
+
+    #[cfg(not(feature = "h264"))]
+    fn decode_video(&mut self, _tile: &Tile, _keyframe: bool) -> Result<Option<Vec<u8>>, String> {
+        Err("H.264 support is not compiled in".to_string())
+    }
+}
+
+fn tile_grid(width: u32, height: u32, tile_size: u32) -> impl Iterator<Item = (u32, u32, u32, u32)> {
+    (0..height).step_by(tile_size as usize).flat_map(move |y| {
+        (0..width).step_by(tile_size as usize).map(move |x| {
+            (x, y, tile_size.min(width - x), tile_size.min(height - y))
+        })
+    })
+}
+
+fn tile_differs(previous: &Frame, frame: &Frame, x: u32, y: u32, width: u32, height: u32) -> bool {
+    let stride = frame.width as usize * 4;
+    let start = x as usize * 4;
+    let end = start + width as usize * 4;
+    (y..y + height).any(|row| {
+        let offset = row as usize * stride;
+        previous.data[offset + start..offset + end] != frame.data[offset + start..offset + end]
+    })
+}
+
+fn copy_tile(frame: &Frame, x: u32, y: u32, width: u32, height: u32) -> Vec<u8> {
+    let stride = frame.width as usize * 4;
+    let start = x as usize * 4;
+    let end = start + width as usize * 4;
+    let mut data = Vec::with_capacity(width as usize * height as usize * 4);
+    for row in y..y + height {
+        let offset = row as usize * stride;
+        data.extend_from_slice(&frame.data[offset + start..offset + end]);
+    }
+    data
+}
+
+fn compress(codec: Codec, quality: u8, pixels: &[u8], width: u32, height: u32) -> Result<Vec<u8>, String> {
+    match codec {
+        Codec::Raw => Ok(pixels.to_vec()),
+        Codec::Zstd => zstd::bulk::compress(pixels, ZSTD_LEVEL).map_err(|e| format!("zstd: {}", e)),
+        Codec::Lz4 => Ok(lz4_flex::compress(pixels)),
+        Codec::Qoi => qoi::encode_to_vec(pixels, width, height).map_err(|e| format!("QOI: {}", e)),
+        Codec::Jpeg => {
+            let mut data = Vec::new();
+            JpegEncoder::new_with_quality(&mut data, quality)
+                .encode(&rgba_to_rgb(pixels), width, height, ExtendedColorType::Rgb8)
+                .map_err(|e| format!("JPEG: {}", e))?;
+            Ok(data)
+        }
+        Codec::WebP => {
+            let rgb = rgba_to_rgb(pixels);
+            Ok(webp::Encoder::from_rgb(&rgb, width, height).encode(quality as f32).to_vec())
+        }
+        Codec::H264 => Err("H.264 frames are encoded as a whole".to_string()),
+    }
+}
+
+// Decompress a tile, never producing more than the tile's own pixel count
+fn decompress(codec: Codec, tile: &Tile) -> Result<Vec<u8>, String> {
+    let expected = tile.width as usize * tile.height as usize * 4;
+    let pixels = match codec {
+        Codec::Raw => tile.data.clone(),
+        Codec::Zstd => zstd::bulk::decompress(&tile.data, expected).map_err(|e| format!("zstd: {}", e))?,
+        Codec::Lz4 => lz4_flex::decompress(&tile.data, expected).map_err(|e| format!("LZ4: {}", e))?,
+        Codec::Qoi => {
+            let mut decoder = qoi::Decoder::new(&tile.data).map_err(|e| format!("QOI: {}", e))?;
+            let header = *decoder.header();
+            if header.width != tile.width || header.height != tile.height || decoder.channels() != qoi::Channels::Rgba {
+                return Err("QOI tile header does not match the tile".to_string());
+            }
+            decoder.decode_to_vec().map_err(|e| format!("QOI: {}", e))?
+        }
+        Codec::Jpeg => {
+            let decoder = JpegDecoder::new(Cursor::new(&tile.data)).map_err(|e| format!("JPEG: {}", e))?;
+            if decoder.dimensions() != (tile.width, tile.height) {
+                return Err("JPEG tile header does not match the tile".to_string());
+            }
+            let image = DynamicImage::from_decoder(decoder).map_err(|e| format!("JPEG: {}", e))?;
+            image.to_rgba8().into_raw()
+        }
+        Codec::WebP => {
+            let features = webp::BitstreamFeatures::new(&tile.data).ok_or("WebP: invalid bitstream")?;
+            if features.width() != tile.width || features.height() != tile.height || features.has_animation() {
+                return Err("WebP tile header does not match the tile".to_string());
+            }
+            let image = webp::Decoder::new(&tile.data).decode().ok_or("WebP: failed to decode tile")?;
+            if image.is_alpha() { image.to_vec() } else { rgb_to_rgba(&image) }
+        }
+        Codec::H264 => return Err("H.264 frames are decoded as a whole".to_string()),
+    };
+    if pixels.len() != expected {
+        return Err(format!("Tile at {},{} has {} bytes, expected {}", tile.x, tile.y, pixels.len(), expected));
+    }
+    Ok(pixels)
+}
+
+fn rgba_to_rgb(pixels: &[u8]) -> Vec<u8> {
+    pixels.chunks_exact(4).flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect()
+}
+
+fn rgb_to_rgba(pixels: &[u8]) -> Vec<u8> {
+    pixels.chunks_exact(3).flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255]).collect()
+}
+
+fn check_tile_bounds(frame: &Frame, tile: &Tile) -> Result<(), String> {
+    let fits = tile.width > 0
+        && tile.height > 0
+        && tile.x.checked_add(tile.width).is_some_and(|right| right <= frame.width)
+        && tile.y.checked_add(tile.height).is_some_and(|bottom| bottom <= frame.height);
+    if !fits {
+        return Err(format!("Tile at {},{} does not fit the {}x{} frame", tile.x, tile.y, frame.width, frame.height));
+    }
+    Ok(())
+}
+
+// Copy decoded pixels into the frame. The tile must have passed check_tile_bounds.
+fn paste_tile(frame: &mut Frame, tile: &Tile, pixels: &[u8]) {
+    let stride = frame.width as usize * 4;
+    let row_len = tile.width as usize * 4;
+    for (i, row) in pixels.chunks_exact(row_len).enumerate() {
+        let offset = (tile.y as usize + i) * stride + tile.x as usize * 4;
+        frame.data[offset..offset + row_len].copy_from_slice(row);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A frame with a different color in every pixel, sized so the last tiles are partial
+    fn test_frame(width: u32, height: u32) -> Frame {
+        let mut data = Vec::with_capacity(width as usize * height as usize * 4);
+        for y in 0..height {
+            for x in 0..width {
+                data.extend_from_slice(&[(x * 3) as u8, (y * 5) as u8, (x ^ y) as u8, 255]);
+            }
+        }
+        Frame { data, width, height }
+    }
+
+    #[test]
+    fn delta_without_keyframe_waits() {
+        let mut encoder = FrameEncoder::new();
+        let frame = test_frame(64, 64);
+        encoder.encode(&frame).unwrap();
+        let delta = encoder.encode(&frame).unwrap();
+        assert!(!delta.keyframe);
+        assert!(FrameDecoder::new(u64::MAX).decode(delta).unwrap().is_none());
+    }
+
+    const LOSSLESS: [Codec; 4] = [Codec::Raw, Codec::Zstd, Codec::Lz4, Codec::Qoi];
+
+    #[test]
+    fn lossless_codecs_round_trip_keyframes_and_deltas() {
+        for codec in LOSSLESS {
+            let mut encoder = FrameEncoder::new();
+            encoder.set_codec(codec);
+            let mut decoder = FrameDecoder::new(u64::MAX);
+
+            let mut frame = test_frame(150, 100);
+            let encoded = encoder.encode(&frame).unwrap();
+            assert!(encoded.keyframe, "{}", codec.name());
+            let decoded = decoder.decode(encoded).unwrap().unwrap();
+            assert!(decoded.data == frame.data, "{} keyframe", codec.name());
+
+            // Change one pixel in the middle tile, only that tile is sent
+            let index = ((70 * 150 + 70) * 4) as usize;
+            frame.data[index..index + 4].copy_from_slice(&[1, 2, 3, 4]);
+            let encoded = encoder.encode(&frame).unwrap();
+            assert!(!encoded.keyframe, "{}", codec.name());
+            assert_eq!(encoded.tiles.len(), 1, "{}", codec.name());
+            let decoded = decoder.decode(encoded).unwrap().unwrap();
+            assert!(decoded.data == frame.data, "{} delta", codec.name());
+        }
+    }
+
+    #[test]
+    fn tiles_outside_the_frame_are_rejected() {
+        for (x, y, width, height) in [(60, 0, 8, 8), (0, 60, 8, 8), (u32::MAX, 0, 2, 2), (0, 0, 0, 8)] {
+            let encoded = EncodedFrame {
+                seq: 1,
+                width: 64,
+                height: 64,
+                keyframe: true,
+                codec: Codec::Raw,
+                tiles: vec![Tile { x, y, width, height, data: vec![0; width as usize * height as usize * 4] }],
+            };
+            assert!(FrameDecoder::new(u64::MAX).decode(encoded).is_err(), "tile at {},{} {}x{}", x, y, width, height);
+        }
+    }
+
+    #[test]
+    fn frames_over_the_pixel_limit_are_rejected() {
+        let frame = test_frame(64, 64);
+        let encoded = FrameEncoder::new().encode(&frame).unwrap();
+        assert!(FrameDecoder::new(64 * 64 - 1).decode(encoded.clone()).is_err());
+        assert!(FrameDecoder::new(64 * 64).decode(encoded).unwrap().is_some());
+
+        let huge = EncodedFrame { seq: 1, width: MAX_DIMENSION + 1, height: 1, keyframe: true, codec: Codec::Raw, tiles: Vec::new() };
+        assert!(FrameDecoder::new(u64::MAX).decode(huge).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+#[cfg(not(target_os = "linux"))]
+use std::io;
+use serde::{Deserialize, Serialize};
+
+// How the mouse pointer reaches receivers
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CursorMode {
+    Hidden,
+    BurnedIn, // Drawn into the frame before encoding
+    Overlay,  // Sent as separate small messages, drawn by the receiver
+}
+
+impl CursorMode {
+    pub const ALL: [CursorMode; 3] = [CursorMode::Hidden, CursorMode::BurnedIn, CursorMode::Overlay];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CursorMode::Hidden => "Hidden",
+            CursorMode::BurnedIn => "In frame",
+            CursorMode::Overlay => "Overlay",
+        }
+    }
+}
+
+// Pointer images are small, anything larger on either side is refused
+pub const MAX_CURSOR_SIZE: u32 = 256;
+
+// The pointer image, straight (not premultiplied) RGBA
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CursorShape {
+    pub width: u32,
+    pub height: u32,
+    pub hot_x: u32, // The pixel that points, from the top-left corner
+    pub hot_y: u32,
+    pub data: Vec<u8>,
+}
+
+impl CursorShape {
+    // Check a shape from the network before it is turned into an image
+    pub fn check(&self) -> Result<(), String> {
+        if self.width > MAX_CURSOR_SIZE || self.height > MAX_CURSOR_SIZE {
+            return Err(format!("Pointer image {}x{} is larger than {}x{}", self.width, self.height, MAX_CURSOR_SIZE, MAX_CURSOR_SIZE));
+        }
+        let expected = (self.width as usize).checked_mul(self.height as usize).and_then(|pixels| pixels.checked_mul(4));
+        if expected != Some(self.data.len()) {
+            return Err(format!("Pointer image data does not match its {}x{} size", self.width, self.height));
+        }
+        if self.hot_x >= self.width || self.hot_y >= self.height {
+            return Err(format!("Pointer hotspot {},{} is outside its {}x{} image", self.hot_x, self.hot_y, self.width, self.height));
+        }
+        Ok(())
+    }
+}
+
+// The pointer as seen by a source
+#[derive(Clone, PartialEq, Debug)]
+pub struct Cursor {
+    pub x: i32, // Where the hotspot is, in frame pixels. May lie outside the frame.
+    pub y: i32,
+    pub shape: CursorShape,
+}
+
+#[cfg(target_os = "linux")]
+pub use x11::CursorReader;
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use std::io;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xfixes::ConnectionExt as _;
+    use x11rb::protocol::xproto::{ConnectionExt as _, Window};
+    use x11rb::rust_connection::RustConnection;
+    use crate::xserver::{self, x11_error};
+    use super::{Cursor, CursorShape};
+
+    // Reads the pointer through the XFixes extension, which captures don't include
+    pub struct CursorReader {
+        conn: RustConnection,
+        root: Window,
+        shape: Option<(u32, CursorShape)>, // Converted image, by XFixes serial
+    }
+
+    impl CursorReader {
+        pub fn new() -> io::Result<Self> {
+            let (conn, screen_num) = xserver::connect()?;
+            let root = conn.setup().roots[screen_num].root;
+            // Required before any other XFixes request
+            conn.xfixes_query_version(4, 0)
+                .map_err(x11_error)?
+                .reply()
+                .map_err(|_| io::Error::new(io::ErrorKind::Unsupported, "XFixes extension not available"))?;
+            Ok(Self { conn, root, shape: None })
+        }
+
+        // Where a window's top-left corner is on the screen
+        pub fn window_origin(&self, window: Window) -> Option<(i32, i32)> {
+            let reply = self.conn.translate_coordinates(window, self.root, 0, 0).ok()?.reply().ok()?;
+            Some((reply.dst_x as i32, reply.dst_y as i32))
+        }
+
+        // The pointer relative to a frame whose top-left corner is at origin on the screen
+        pub fn read(&mut self, origin: (i32, i32)) -> Option<Cursor> {
+            let image = self.conn.xfixes_get_cursor_image().ok()?.reply().ok()?;
+            // The image only changes when the serial does, skip converting it again
+            if self.shape.as_ref().map(|(serial, _)| *serial) != Some(image.cursor_serial) {
+                let mut data = Vec::with_capacity(image.cursor_image.len() * 4);
+                for pixel in &image.cursor_image {
+                    // Premultiplied ARGB
+                    let [b, g, r, a] = pixel.to_le_bytes();
+                    let unpremultiply = |c: u8| if a == 0 { 0 } else { (c as u32 * 255 / a as u32).min(255) as u8 };
+                    data.extend_from_slice(&[unpremultiply(r), unpremultiply(g), unpremultiply(b), a]);
+                }
+                let shape = CursorShape {
+                    width: image.width as u32,
+                    height: image.height as u32,
+                    hot_x: image.xhot as u32,
+                    hot_y: image.yhot as u32,
+                    data,
+                };
+                self.shape = Some((image.cursor_serial, shape));
+            }
+            let (_, shape) = self.shape.as_ref()?;
+            Some(Cursor {
+                x: image.x as i32 - origin.0,
+                y: image.y as i32 - origin.1,
+                shape: shape.clone(),
+            })
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct CursorReader;
+
+#[cfg(not(target_os = "linux"))]
+impl CursorReader {
+    pub fn new() -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Cursor capture is only supported on X11"))
+    }
+
+    pub fn read(&mut self, _origin: (i32, i32)) -> Option<Cursor> {
+        None
+    }
+}
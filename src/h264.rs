@@ -0,0 +1,64 @@
+use openh264::decoder::Decoder;
+use openh264::encoder::{BitRate, Encoder, EncoderConfig, FrameRate, IntraFramePeriod, RateControlMode, UsageType};
+use openh264::formats::{RgbaSliceU8, YUVBuffer, YUVSource};
+use openh264::OpenH264API;
+use crate::codec::H264Settings;
+
+// Wraps an OpenH264 encoder for one stream of fixed-size frames.
+// H.264 needs even dimensions, so callers pass frames already trimmed to even sizes.
+pub struct H264Encoder {
+    encoder: Encoder,
+    pub width: u32,
+    pub height: u32,
+    pub settings: H264Settings,
+}
+
+impl H264Encoder {
+    pub fn new(width: u32, height: u32, settings: H264Settings) -> Result<Self, String> {
+        let config = EncoderConfig::new()
+            .usage_type(UsageType::ScreenContentRealTime)
+            .rate_control_mode(RateControlMode::Bitrate)
+            .bitrate(BitRate::from_bps(settings.bitrate_kbps.saturating_mul(1000)))
+            .max_frame_rate(FrameRate::from_hz(settings.frame_rate.max(1) as f32))
+            .skip_frames(false)
+            // Keyframes are placed by FrameEncoder so they line up with the wire protocol
+            .intra_frame_period(IntraFramePeriod::from_num_frames(0));
+        let encoder = Encoder::with_api_config(OpenH264API::from_source(), config)
+            .map_err(|e| format!("H.264: {}", e))?;
+        Ok(Self { encoder, width, height, settings })
+    }
+
+    pub fn encode(&mut self, rgba: &[u8], keyframe: bool) -> Result<Vec<u8>, String> {
+        let source = RgbaSliceU8::new(rgba, (self.width as usize, self.height as usize));
+        let yuv = YUVBuffer::from_rgba8_source(source);
+        if keyframe {
+            self.encoder.force_intra_frame();
+        }
+        let bitstream = self.encoder.encode(&yuv).map_err(|e| format!("H.264: {}", e))?;
+        Ok(bitstream.to_vec())
+    }
+}
+
+pub struct H264Decoder {
+    decoder: Decoder,
+}
+
+impl H264Decoder {
+    pub fn new() -> Result<Self, String> {
+        let decoder = Decoder::new().map_err(|e| format!("H.264: {}", e))?;
+        Ok(Self { decoder })
+    }
+
+    // Decode one access unit into RGBA. Returns None if the decoder has no picture yet.
+    pub fn decode(&mut self, data: &[u8], width: u32, height: u32) -> Result<Option<Vec<u8>>, String> {
+        let Some(yuv) = self.decoder.decode(data).map_err(|e| format!("H.264: {}", e))? else {
+            return Ok(None);
+        };
+        if yuv.dimensions() != (width as usize, height as usize) {
+            return Err("H.264 picture size does not match the frame".to_string());
+        }
+        let mut rgba = vec![0; width as usize * height as usize * 4];
+        yuv.write_rgba8(&mut rgba);
+        Ok(Some(rgba))
+    }
+}
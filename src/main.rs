@@ -4,6 +4,19 @@ mod caster;
 mod screen;
 mod client;
 mod server;
+mod source;
+mod pattern;
+mod selection;
+mod window;
+mod cursor;
+mod masks;
+mod pipeline;
+mod codec;
+mod protocol;
+#[cfg(feature = "h264")]
+mod h264;
+#[cfg(target_os = "linux")]
+mod xserver;
 
 fn main() {
     let app = app::UStreamApp::default();
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use crate::screen::MaskRegion;
+
+// Privacy masks by the name of the source they cover, kept between runs
+pub type SavedMasks = HashMap<String, Vec<MaskRegion>>;
+
+// In the per-user config directory, ~/.config/ustream on Linux
+fn masks_path() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config.join("ustream").join("masks.bin"))
+}
+
+pub fn load_masks() -> SavedMasks {
+    let Some(path) = masks_path() else {
+        return SavedMasks::new();
+    };
+    let Ok(data) = fs::read(&path) else {
+        return SavedMasks::new(); // Nothing saved yet
+    };
+    bincode::deserialize(&data).unwrap_or_else(|e| {
+        eprintln!("Ignoring unreadable privacy masks in {}: {}", path.display(), e);
+        SavedMasks::new()
+    })
+}
+
+pub fn save_masks(masks: &SavedMasks) -> Result<(), String> {
+    let path = masks_path().ok_or("No config directory to save privacy masks in")?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    let data = bincode::serialize(masks).map_err(|e| format!("Failed to save privacy masks: {}", e))?;
+    fs::write(&path, data).map_err(|e| format!("Failed to save privacy masks to {}: {}", path.display(), e))
+}
@@ -0,0 +1,202 @@
+use std::io;
+use std::time::{Duration, Instant};
+use crate::screen::Frame;
+use crate::source::FrameSource;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Pattern {
+    ColorBars,
+    MovingBox,
+    Gradient,
+}
+
+impl Pattern {
+    pub const ALL: [Pattern; 3] = [Pattern::ColorBars, Pattern::MovingBox, Pattern::Gradient];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pattern::ColorBars => "Color Bars",
+            Pattern::MovingBox => "Moving Box",
+            Pattern::Gradient => "Gradient",
+        }
+    }
+}
+
+// 3x5 bitmaps for the digits 0-9, one row per entry, most significant bit on the left
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+// SMPTE-style bars: white, yellow, cyan, green, magenta, red, blue, black
+const BARS: [[u8; 3]; 8] = [
+    [255, 255, 255],
+    [255, 255, 0],
+    [0, 255, 255],
+    [0, 255, 0],
+    [255, 0, 255],
+    [255, 0, 0],
+    [0, 0, 255],
+    [0, 0, 0],
+];
+
+// Synthetic source producing deterministic animated frames, for machines without a monitor.
+// Every frame has its sequence number burned into the top-left corner.
+pub struct TestPatternSource {
+    pattern: Pattern,
+    width: u32,
+    height: u32,
+    interval: Duration,
+    next_due: Instant,
+    frame_number: u64,
+}
+
+impl TestPatternSource {
+    pub fn new(pattern: Pattern, width: u32, height: u32, fps: u32) -> io::Result<Self> {
+        if width == 0 || height == 0 || fps == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Test pattern size and rate must be non-zero"));
+        }
+        Ok(Self {
+            pattern,
+            width,
+            height,
+            interval: Duration::from_secs(1) / fps,
+            next_due: Instant::now(),
+            frame_number: 0,
+        })
+    }
+
+    // Render the frame with the given sequence number
+    pub fn render(&self, frame_number: u64) -> Frame {
+        let mut frame = Frame {
+            data: vec![0; (self.width * self.height * 4) as usize],
+            width: self.width,
+            height: self.height,
+        };
+
+        match self.pattern {
+            Pattern::ColorBars => draw_color_bars(&mut frame, frame_number),
+            Pattern::MovingBox => draw_moving_box(&mut frame, frame_number),
+            Pattern::Gradient => draw_gradient(&mut frame, frame_number),
+        }
+        draw_counter(&mut frame, frame_number);
+        frame
+    }
+}
+
+impl FrameSource for TestPatternSource {
+    fn next_frame(&mut self) -> io::Result<Frame> {
+        let now = Instant::now();
+        if now < self.next_due {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        // Don't try to catch up on missed frames, just keep the cadence
+        self.next_due = (self.next_due + self.interval).max(now);
+
+        let frame = self.render(self.frame_number);
+        self.frame_number += 1;
+        Ok(frame)
+    }
+}
+
+fn put_pixel(frame: &mut Frame, x: usize, y: usize, rgb: [u8; 3]) {
+    let index = (y * frame.width as usize + x) * 4;
+    frame.data[index..index + 4].copy_from_slice(&[rgb[0], rgb[1], rgb[2], 255]);
+}
+
+fn fill_rect(frame: &mut Frame, x: usize, y: usize, w: usize, h: usize, rgb: [u8; 3]) {
+    let x_end = (x + w).min(frame.width as usize);
+    let y_end = (y + h).min(frame.height as usize);
+    for py in y..y_end {
+        for px in x..x_end {
+            put_pixel(frame, px, py, rgb);
+        }
+    }
+}
+
+fn draw_color_bars(frame: &mut Frame, frame_number: u64) {
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+    // Scroll the bars one pixel per frame so motion is visible
+    let offset = frame_number as usize % width;
+    for y in 0..height {
+        for x in 0..width {
+            let bar = ((x + offset) % width) * BARS.len() / width;
+            put_pixel(frame, x, y, BARS[bar]);
+        }
+    }
+}
+
+fn draw_moving_box(frame: &mut Frame, frame_number: u64) {
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+    fill_rect(frame, 0, 0, width, height, [32, 32, 32]);
+
+    // Bounce a square back and forth along both axes
+    let size = (width.min(height) / 8).max(1);
+    let travel_x = (width - size).max(1);
+    let travel_y = (height - size).max(1);
+    let step = frame_number as usize * 4;
+    let x = bounce(step, travel_x);
+    let y = bounce(step, travel_y);
+    fill_rect(frame, x, y, size, size, [255, 128, 0]);
+}
+
+fn bounce(step: usize, travel: usize) -> usize {
+    let position = step % (2 * travel);
+    if position < travel { position } else { 2 * travel - position }
+}
+
+fn draw_gradient(frame: &mut Frame, frame_number: u64) {
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+    let max_x = (width - 1).max(1);
+    let max_y = (height - 1).max(1);
+    let shift = (frame_number % 256) as usize;
+    for y in 0..height {
+        for x in 0..width {
+            let r = (x * 255 / max_x) as u8;
+            let g = (y * 255 / max_y) as u8;
+            let b = ((x + y + shift) % 256) as u8;
+            put_pixel(frame, x, y, [r, g, b]);
+        }
+    }
+}
+
+fn draw_counter(frame: &mut Frame, frame_number: u64) {
+    let text = frame_number.to_string();
+    let scale = ((frame.height as usize) / 60).max(2);
+    let margin = scale * 2;
+    let glyph_width = 3 * scale;
+    let glyph_height = 5 * scale;
+
+    // Black plate behind the digits so they stay readable on every pattern
+    fill_rect(
+        frame,
+        0,
+        0,
+        margin * 2 + text.len() * (glyph_width + scale) - scale,
+        margin * 2 + glyph_height,
+        [0, 0, 0],
+    );
+
+    for (i, digit) in text.bytes().enumerate() {
+        let bitmap = DIGITS[(digit - b'0') as usize];
+        let origin_x = margin + i * (glyph_width + scale);
+        for (row, bits) in bitmap.iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    fill_rect(frame, origin_x + col * scale, margin + row * scale, scale, scale, [255, 255, 255]);
+                }
+            }
+        }
+    }
+}
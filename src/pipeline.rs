@@ -0,0 +1,116 @@
+use std::sync::{Arc, Mutex};
+use crate::screen::{Frame, FrameSink, CropValues, MaskRegion, MaskStyle, Resolution, crop, crop_to_region, blank, draw_cursor, mask, resize};
+use crate::cursor::{Cursor, CursorMode};
+use crate::protocol::CursorState;
+use crate::server::ChannelSender;
+
+// What the caster has set for one source. Copied in by the UI, read for every frame.
+#[derive(Clone)]
+pub struct CastSettings {
+    pub crop: CropValues,
+    pub trim_crop: bool, // Send only the cropped region instead of painting the margins white
+    pub resolution: Resolution,
+    pub masks: Vec<MaskRegion>,
+    pub cursor_mode: CursorMode,
+    pub is_streaming: bool,
+    pub is_blank: bool,
+}
+
+// Turns captured frames into what receivers see and sends them, on the capture thread
+pub struct CastPipeline {
+    settings: Arc<Mutex<CastSettings>>,
+    channel: ChannelSender,
+    sizes: Option<(u32, u32, u32)>, // Last captured width and height, and outgoing width
+    mask_areas: Option<Vec<(CropValues, MaskStyle)>>, // What the last frame was masked with
+}
+
+impl CastPipeline {
+    pub fn new(settings: Arc<Mutex<CastSettings>>, channel: ChannelSender) -> Self {
+        Self { settings, channel, sizes: None, mask_areas: None }
+    }
+
+    // Tell receivers where to draw the pointer. Also sent when the overlay is off,
+    // so receivers hide the last pointer.
+    fn send_cursor(&self, pointer: Option<&Cursor>, settings: &CastSettings) {
+        let Some((width, height, outgoing_width)) = self.sizes else {
+            return; // Nothing to place it on yet
+        };
+        if !settings.is_streaming {
+            return;
+        }
+        let state = match (pointer, settings.cursor_mode) {
+            (Some(pointer), CursorMode::Overlay) if !settings.is_blank => {
+                overlay_cursor(pointer, &settings.crop, settings.trim_crop, width, height, outgoing_width)
+            }
+            _ => CursorState { x: 0.0, y: 0.0, scale: 1.0, visible: false },
+        };
+        // Receivers refuse images they can't draw, those are left out
+        let shape = pointer
+            .filter(|pointer| state.visible && pointer.shape.check().is_ok())
+            .map(|pointer| &pointer.shape);
+        self.channel.broadcast_cursor(state, shape);
+    }
+}
+
+impl FrameSink for CastPipeline {
+    fn frame(&mut self, mut frame: Frame, pointer: Option<Cursor>) -> Frame {
+        let settings = self.settings.lock().unwrap().clone();
+        // Frames sent so far show what the changed masks may now hide. The channel starts
+        // over here, so the keyframe it asks for is made with the same masks as this frame.
+        // Renaming a mask changes nothing on screen and doesn't count.
+        let mask_areas: Vec<_> = settings.masks.iter().map(|region| (region.area.clone(), region.style)).collect();
+        if self.mask_areas.as_ref().is_some_and(|previous| *previous != mask_areas) {
+            self.channel.reset();
+        }
+        self.mask_areas = Some(mask_areas);
+        // Masked first, so the hidden pixels are not in the preview or anything sent
+        for region in &settings.masks {
+            mask(&mut frame, region);
+        }
+        if let (Some(pointer), CursorMode::BurnedIn) = (&pointer, settings.cursor_mode) {
+            draw_cursor(&mut frame, pointer);
+        }
+        blank(&mut frame, settings.is_blank);
+        let mut outgoing = if settings.trim_crop {
+            crop_to_region(&frame, &settings.crop)
+        } else {
+            let mut painted = frame.clone();
+            crop(&mut painted, settings.crop.clone());
+            painted
+        };
+        let (width, height) = settings.resolution.fit(outgoing.width, outgoing.height);
+        if (width, height) != (outgoing.width, outgoing.height) {
+            outgoing = resize(&outgoing, width, height);
+        }
+        self.sizes = Some((frame.width, frame.height, outgoing.width));
+        self.send_cursor(pointer.as_ref(), &settings);
+        self.channel.broadcast_frame(outgoing, settings.is_streaming, settings.is_blank);
+        // The preview keeps the whole frame, the selection is drawn over it
+        frame
+    }
+
+    fn cursor(&mut self, pointer: Option<Cursor>) {
+        // Drawn into the next frame when burned in, only the overlay moves on its own
+        let settings = self.settings.lock().unwrap();
+        if settings.cursor_mode == CursorMode::Overlay {
+            self.send_cursor(pointer.as_ref(), &settings);
+        }
+    }
+}
+
+// Where receivers draw the pointer over the outgoing frame. Hidden when it is outside the crop.
+fn overlay_cursor(pointer: &Cursor, crop: &CropValues, trim_crop: bool, frame_width: u32, frame_height: u32, outgoing_width: u32) -> CursorState {
+    let (left, top, width, height) = crop.region(frame_width, frame_height);
+    let visible = pointer.x >= left as i32
+        && pointer.y >= top as i32
+        && pointer.x < (left + width) as i32
+        && pointer.y < (top + height) as i32;
+    // Painted margins are still part of the outgoing frame
+    let (left, top, width, height) = if trim_crop { (left, top, width, height) } else { (0, 0, frame_width, frame_height) };
+    CursorState {
+        x: (pointer.x - left as i32) as f32 / width as f32,
+        y: (pointer.y - top as i32) as f32 / height as f32,
+        scale: outgoing_width as f32 / width as f32,
+        visible,
+    }
+}
@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::codec::{Codec, EncodedFrame};
+use crate::cursor::CursorShape;
+
+// Every connection starts with these bytes from both sides, so peers from
+// another program or an incompatible build are rejected before any decoding
+pub const MAGIC: [u8; 4] = *b"USTR";
+pub const PROTOCOL_VERSION: u16 = 5;
+pub const DEFAULT_PORT: u16 = 9041;
+// The caster sends a Ping when nothing else was sent for this long, so a
+// receiver that hears nothing for a few intervals knows the connection is dead
+pub const PING_INTERVAL: Duration = Duration::from_secs(2);
+// Largest message a receiver accepts unless configured otherwise, enough for an uncompressed 8K keyframe
+pub const DEFAULT_MAX_MESSAGE_SIZE: u32 = 256 * 1024 * 1024;
+// Largest frame a receiver decodes unless configured otherwise, 8K. Bounds the
+// frame buffer a keyframe header can make the receiver allocate.
+pub const DEFAULT_MAX_FRAME_PIXELS: u64 = 7680 * 4320;
+// The hello is read before any limit is negotiated, so it gets a small fixed one
+const MAX_HELLO_SIZE: u32 = 64 * 1024;
+
+// Sent by both sides right after the preamble
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hello {
+    pub codecs: Vec<Codec>, // Codecs this side can encode (caster) or decode (receiver)
+    pub max_message_size: u32, // Largest message this side is willing to read
+    pub max_frame_pixels: u64, // Largest frame, width times height, this side is willing to decode
+    pub channels: Vec<String>, // Streams the caster offers, empty from a receiver
+    pub channel: Option<String>, // Stream the receiver wants, None for the caster's first
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct StreamMetadata {
+    pub width: u32,
+    pub height: u32,
+    pub codec: Codec,
+}
+
+// Where the receiver draws the pointer, as a fraction of the frame size
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CursorState {
+    pub x: f32,
+    pub y: f32,
+    pub scale: f32, // Frame pixels per pointer image pixel
+    pub visible: bool,
+}
+
+impl CursorState {
+    // Check a position from the network before it is drawn
+    pub fn check(&self) -> Result<(), String> {
+        if !self.x.is_finite() || !self.y.is_finite() || !self.scale.is_finite() || self.scale <= 0.0 {
+            return Err(format!("Invalid pointer position {},{} at scale {}", self.x, self.y, self.scale));
+        }
+        Ok(())
+    }
+}
+
+// Everything the caster sends after the handshake.
+// On the wire each message is a 4-byte big-endian length followed by the bincode message.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Message {
+    Frame(EncodedFrame),
+    Paused,
+    Blanked,
+    Metadata(StreamMetadata),
+    Ping,
+    Goodbye(String),
+    Cursor(CursorState),
+    CursorShape(CursorShape), // Sent when the pointer image changes
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Closed, // The peer closed the connection
+    Io(io::Error),
+    Invalid(String), // The peer sent something this build can't accept
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Closed => write!(f, "Connection closed by peer"),
+            ProtocolError::Io(e) => write!(f, "Connection error: {}", e),
+            ProtocolError::Invalid(reason) => write!(f, "Protocol error: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            ProtocolError::Closed
+        } else {
+            ProtocolError::Io(e)
+        }
+    }
+}
+
+impl Hello {
+    pub fn local() -> Self {
+        Self {
+            codecs: Codec::available().collect(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_frame_pixels: DEFAULT_MAX_FRAME_PIXELS,
+            channels: Vec::new(),
+            channel: None,
+        }
+    }
+}
+
+// Serialize a message together with its length prefix
+pub fn encode_message(message: &Message) -> Result<Vec<u8>, ProtocolError> {
+    let payload = bincode::serialize(message).map_err(|e| ProtocolError::Invalid(e.to_string()))?;
+    let mut buffer = Vec::with_capacity(4 + payload.len());
+    buffer.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&payload);
+    Ok(buffer)
+}
+
+// Read one message, rejecting any that claims to be larger than max_size.
+// Fails with a TimedOut error once nothing has arrived for silence. The wait starts
+// over with every chunk received, so a large message on a slow link is not cut off.
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R, max_size: u32, silence: Duration) -> Result<Message, ProtocolError> {
+    let payload = read_payload(reader, max_size, Some(silence)).await?;
+    bincode::deserialize(&payload).map_err(|e| ProtocolError::Invalid(format!("Malformed message: {}", e)))
+}
+
+// Send our preamble and hello, then check the peer's
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, hello: &Hello) -> Result<Hello, ProtocolError> {
+    let hello = bincode::serialize(hello).map_err(|e| ProtocolError::Invalid(e.to_string()))?;
+    let mut buffer = Vec::with_capacity(10 + hello.len());
+    buffer.extend_from_slice(&MAGIC);
+    buffer.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    buffer.extend_from_slice(&(hello.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&hello);
+    stream.write_all(&buffer).await?;
+
+    let mut preamble = [0u8; 6];
+    stream.read_exact(&mut preamble).await?;
+    if preamble[..4] != MAGIC {
+        return Err(ProtocolError::Invalid("Peer is not running a compatible version of UStream".to_string()));
+    }
+    let version = u16::from_be_bytes([preamble[4], preamble[5]]);
+    if version != PROTOCOL_VERSION {
+        return Err(ProtocolError::Invalid(format!(
+            "Protocol version mismatch: this build speaks version {}, the peer speaks version {}",
+            PROTOCOL_VERSION, version
+        )));
+    }
+
+    // The caller limits the handshake as a whole
+    let payload = read_payload(stream, MAX_HELLO_SIZE, None).await?;
+    bincode::deserialize(&payload).map_err(|e| ProtocolError::Invalid(format!("Malformed hello: {}", e)))
+}
+
+async fn read_payload<R: AsyncRead + Unpin>(reader: &mut R, max_size: u32, silence: Option<Duration>) -> Result<Vec<u8>, ProtocolError> {
+    let mut size_buffer = [0u8; 4];
+    read_exact_within(reader, &mut size_buffer, silence).await?;
+    let size = u32::from_be_bytes(size_buffer);
+    // Check before allocating, the length comes straight from the peer
+    if size > max_size {
+        return Err(ProtocolError::Invalid(format!(
+            "Message of {} bytes exceeds the limit of {} bytes",
+            size, max_size
+        )));
+    }
+
+    let mut payload = vec![0u8; size as usize];
+    read_exact_within(reader, &mut payload, silence).await?;
+    Ok(payload)
+}
+
+// Fill the buffer, giving up when no bytes arrive for silence
+async fn read_exact_within<R: AsyncRead + Unpin>(reader: &mut R, buffer: &mut [u8], silence: Option<Duration>) -> Result<(), ProtocolError> {
+    let Some(silence) = silence else {
+        reader.read_exact(buffer).await?;
+        return Ok(());
+    };
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match tokio::time::timeout(silence, reader.read(&mut buffer[filled..])).await {
+            Ok(Ok(0)) => return Err(ProtocolError::Closed),
+            Ok(Ok(read)) => filled += read,
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(ProtocolError::Io(io::ErrorKind::TimedOut.into())),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    // What a peer sends before its hello
+    fn preamble(magic: &[u8; 4], version: u16) -> Vec<u8> {
+        let hello = bincode::serialize(&Hello::local()).unwrap();
+        let mut buffer = magic.to_vec();
+        buffer.extend_from_slice(&version.to_be_bytes());
+        buffer.extend_from_slice(&(hello.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(&hello);
+        buffer
+    }
+
+    async fn handshake_with(peer_bytes: Vec<u8>) -> Result<Hello, ProtocolError> {
+        let (mut ours, mut theirs) = duplex(64 * 1024);
+        theirs.write_all(&peer_bytes).await.unwrap();
+        handshake(&mut ours, &Hello::local()).await
+    }
+
+    #[tokio::test]
+    async fn handshake_accepts_the_same_version() {
+        let hello = handshake_with(preamble(&MAGIC, PROTOCOL_VERSION)).await.unwrap();
+        assert_eq!(hello.max_frame_pixels, DEFAULT_MAX_FRAME_PIXELS);
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_another_version() {
+        let error = handshake_with(preamble(&MAGIC, PROTOCOL_VERSION + 1)).await.unwrap_err();
+        assert!(matches!(&error, ProtocolError::Invalid(reason) if reason.contains("version")), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_another_magic() {
+        let error = handshake_with(preamble(b"HTTP", PROTOCOL_VERSION)).await.unwrap_err();
+        assert!(matches!(error, ProtocolError::Invalid(_)), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn messages_over_the_size_limit_are_rejected() {
+        let bytes = encode_message(&Message::Goodbye("x".repeat(100))).unwrap();
+        let limit = bytes.len() as u32 - 4;
+        let error = read_message(&mut &bytes[..], limit - 1, PING_INTERVAL).await.err().unwrap();
+        assert!(matches!(error, ProtocolError::Invalid(_)), "{}", error);
+        let message = read_message(&mut &bytes[..], limit, PING_INTERVAL).await.unwrap();
+        assert!(matches!(message, Message::Goodbye(reason) if reason.len() == 100));
+    }
+
+    #[tokio::test]
+    async fn oversized_length_is_rejected_before_reading_the_payload() {
+        // Only the length prefix arrives, the check must not wait for the rest
+        let bytes = u32::MAX.to_be_bytes();
+        let error = read_message(&mut &bytes[..], DEFAULT_MAX_MESSAGE_SIZE, PING_INTERVAL).await.err().unwrap();
+        assert!(matches!(error, ProtocolError::Invalid(_)), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn slow_messages_arrive_and_silence_times_out() {
+        let silence = Duration::from_millis(100);
+        let bytes = encode_message(&Message::Goodbye("x".repeat(1000))).unwrap();
+        let (mut ours, mut theirs) = duplex(64 * 1024);
+        // Takes longer than the silence in total, but never pauses that long
+        let writer = tokio::spawn(async move {
+            for chunk in bytes.chunks(200) {
+                theirs.write_all(chunk).await.unwrap();
+                tokio::time::sleep(silence / 2).await;
+            }
+            theirs
+        });
+        let message = read_message(&mut ours, DEFAULT_MAX_MESSAGE_SIZE, silence).await.unwrap();
+        assert!(matches!(message, Message::Goodbye(reason) if reason.len() == 1000));
+
+        // Half a message, then nothing
+        let mut theirs = writer.await.unwrap();
+        let ping = encode_message(&Message::Ping).unwrap();
+        theirs.write_all(&ping[..ping.len() - 1]).await.unwrap();
+        let error = read_message(&mut ours, DEFAULT_MAX_MESSAGE_SIZE, silence).await.err().unwrap();
+        assert!(matches!(&error, ProtocolError::Io(e) if e.kind() == io::ErrorKind::TimedOut), "{}", error);
+    }
+}
@@ -1,9 +1,34 @@
 use eframe::egui;
-use crate::client::{DisconnectHandle,connect_to_server};
-use tokio::sync::mpsc;
+use crate::client::{ClientEvent,DisconnectHandle,connect_to_server};
+use tokio::sync::{mpsc, oneshot};
 use tokio::runtime::Runtime;
 use std::sync::Arc;
-use crate::screen::{Frame};
+use std::time::{Duration, Instant};
+use crate::screen::{Frame, blank};
+use crate::protocol::{CursorState, StreamMetadata};
+use crate::cursor::CursorShape;
+
+type ConnectResult = Result<(mpsc::Receiver<ClientEvent>, DisconnectHandle), String>;
+
+// Backoff doubles from the first delay up to the cap
+const RECONNECT_FIRST_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// State of an automatic reconnect after the connection dropped
+struct Reconnect {
+    attempt: u32,
+    retry_at: Instant,
+    pending: Option<oneshot::Receiver<ConnectResult>>, // Attempt currently running
+    last_error: Option<String>,
+}
+
+impl Reconnect {
+    fn delay(attempt: u32) -> Duration {
+        RECONNECT_FIRST_DELAY
+            .saturating_mul(1 << attempt.min(16))
+            .min(RECONNECT_MAX_DELAY)
+    }
+}
 
 pub struct Receiver {
     ip_address: String,
@@ -11,8 +36,20 @@ pub struct Receiver {
     error_message: Option<String>,
     disconnect_handle: Option<DisconnectHandle>,
     runtime: Arc<Runtime>,
-    frame_receiver: Option<mpsc::Receiver<Option<Frame>>>,
+    frame_receiver: Option<mpsc::Receiver<ClientEvent>>,
     current_frame: Option<Frame>,
+    metadata: Option<StreamMetadata>,
+    is_paused: bool,
+    auto_reconnect: bool,
+    reconnect: Option<Reconnect>,
+    // Reconnect attempts so far, kept until a frame arrives. A caster that accepts
+    // and then turns us away right after must not reset the backoff.
+    reconnect_attempts: u32,
+    channel: Option<String>, // The caster's stream being watched, None for its first
+    channels: Vec<String>,   // Streams the connected caster offers
+    cursor: Option<CursorState>,
+    cursor_shape: Option<CursorShape>,
+    cursor_texture: Option<egui::TextureHandle>, // Made from cursor_shape when first drawn
 }
 
 impl Receiver {
@@ -27,6 +64,16 @@ impl Receiver {
             runtime,
             frame_receiver: None,
             current_frame: None,
+            metadata: None,
+            is_paused: false,
+            auto_reconnect: false,
+            reconnect: None,
+            reconnect_attempts: 0,
+            channel: None,
+            channels: Vec::new(),
+            cursor: None,
+            cursor_shape: None,
+            cursor_texture: None,
         }
     }
 
@@ -38,22 +85,23 @@ impl Receiver {
             ui.colored_label(egui::Color32::RED, error);
         }
 
-        // Input field for the IP Address
+        // Input field for the caster address
+        let active = self.connected || self.reconnect.is_some();
         ui.horizontal(|ui| {
-            if self.connected {
+            if active {
                 // Render the disabled input by making it non-editable
                 ui.add_enabled(
                     false,
-                    egui::TextEdit::singleline(&mut self.ip_address).hint_text("Enter IP Address"),
+                    egui::TextEdit::singleline(&mut self.ip_address).hint_text("Host or IP, optionally with :port"),
                 );
             } else {
                 ui.add(
-                    egui::TextEdit::singleline(&mut self.ip_address).hint_text("Enter IP Address"),
+                    egui::TextEdit::singleline(&mut self.ip_address).hint_text("Host or IP, optionally with :port"),
                 );
             }
 
             // Button group
-            if self.connected {
+            if active {
                 if ui
                     .add(egui::Button::new("Disconnect").fill(egui::Color32::RED))
                     .clicked()
@@ -65,31 +113,108 @@ impl Receiver {
                     .add(egui::Button::new("Connect").fill(egui::Color32::GREEN))
                     .clicked()
                 {
+                    // A new caster may not have the channel watched on the last one
+                    self.channel = None;
                     self.handle_connect();
                 }
             }
+
+            ui.checkbox(&mut self.auto_reconnect, "Reconnect automatically");
         });
 
+        // Only worth a choice when the caster shares more than one thing
+        if self.connected && self.channels.len() > 1 {
+            let current = self.channel.clone().unwrap_or_else(|| self.channels[0].clone());
+            let mut selected = current.clone();
+            ui.horizontal(|ui| {
+                ui.label("Channel:");
+                egui::ComboBox::from_id_source("channel")
+                    .selected_text(&selected)
+                    .show_ui(ui, |ui| {
+                        for channel in &self.channels {
+                            ui.selectable_value(&mut selected, channel.clone(), channel);
+                        }
+                    });
+            });
+            if selected != current {
+                self.handle_disconnect();
+                self.channel = Some(selected);
+                self.handle_connect();
+            }
+        }
+
+        self.poll_reconnect();
+        if let Some(reconnect) = &self.reconnect {
+            let status = match reconnect.attempt {
+                0 => "Connection lost, reconnecting...".to_string(),
+                attempt => format!("Reconnecting (attempt {})", attempt),
+            };
+            ui.colored_label(egui::Color32::YELLOW, status);
+            if let Some(error) = &reconnect.last_error {
+                ui.small(error);
+            }
+            // Nothing else wakes the UI while waiting for the next attempt
+            ctx.request_repaint_after(Duration::from_millis(250));
+        }
+
         ui.add_space(20.0);
 
         // Display received frames if connected
         if self.connected {
             if let Some(frame_rx) = &mut self.frame_receiver {
-                if let Ok(frame) = frame_rx.try_recv() {
-                    match frame{
-                        Some(frame) => {
-                            self.current_frame = Some(frame.clone());
+                while let Ok(event) = frame_rx.try_recv() {
+                    match event {
+                        ClientEvent::Frame(frame) => {
+                            self.reconnect_attempts = 0;
+                            self.current_frame = Some(frame);
+                            self.is_paused = false;
+                        }
+                        ClientEvent::Paused => self.is_paused = true,
+                        ClientEvent::Blanked => {
+                            self.is_paused = false;
+                            if let Some(frame) = &mut self.current_frame {
+                                blank(frame, true);
+                            }
                         }
-                        None => {
-                            println!("Connection closed by server, stopping receiver.");
+                        ClientEvent::Metadata(metadata) => self.metadata = Some(metadata),
+                        ClientEvent::Channels(channels) => self.channels = channels,
+                        ClientEvent::Cursor(state) => self.cursor = Some(state),
+                        ClientEvent::CursorShape(shape) => {
+                            self.cursor_shape = Some(shape);
+                            self.cursor_texture = None;
+                        }
+                        ClientEvent::Disconnected(reason) => {
                             self.connected = false;
-                            self.current_frame = None;
+                            self.disconnect_handle = None;
+                            self.is_paused = false;
+                            if self.auto_reconnect {
+                                // Keep the last frame on screen while retrying
+                                println!("Connection lost, reconnecting.");
+                                self.reconnect = Some(Reconnect {
+                                    attempt: self.reconnect_attempts,
+                                    retry_at: Instant::now() + Reconnect::delay(self.reconnect_attempts),
+                                    pending: None,
+                                    last_error: reason.map(|reason| format!("Disconnected: {}", reason)),
+                                });
+                            } else {
+                                println!("Connection closed by server, stopping receiver.");
+                                self.current_frame = None;
+                                self.metadata = None;
+                                self.cursor = None;
+                                self.error_message = reason.map(|reason| format!("Disconnected: {}", reason));
+                            }
+                            break;
                         }
                     }
                 }
             }
         }
 
+        if let Some(metadata) = &self.metadata {
+            let status = if self.is_paused { " (paused)" } else { "" };
+            ui.label(format!("{}x{} {}{}", metadata.width, metadata.height, metadata.codec.name(), status));
+        }
+
         if let Some(frame) = &self.current_frame {
             let width = frame.width as usize;
             let height = frame.height as usize;
@@ -114,8 +239,30 @@ impl Receiver {
                 egui::vec2(available_size.x, available_size.x / aspect_ratio)
             };
 
-            // Display the image
-            ui.add(egui::Image::new(&image_handle).fit_to_exact_size(target_size));
+            // Display the image, with the caster's pointer on top
+            let image_rect = ui.add(egui::Image::new(&image_handle).fit_to_exact_size(target_size)).rect;
+            if let (Some(state), Some(shape)) = (&self.cursor, &self.cursor_shape) {
+                if state.visible {
+                    let texture = self.cursor_texture.get_or_insert_with(|| {
+                        let image = egui::ColorImage::from_rgba_unmultiplied(
+                            [shape.width as usize, shape.height as usize],
+                            &shape.data,
+                        );
+                        ctx.load_texture("cursor", image, Default::default())
+                    });
+                    // Pointer pixels are scaled like the frame's
+                    let zoom = image_rect.width() / width as f32 * state.scale;
+                    let hotspot = image_rect.min + egui::vec2(state.x * image_rect.width(), state.y * image_rect.height());
+                    let min = hotspot - egui::vec2(shape.hot_x as f32, shape.hot_y as f32) * zoom;
+                    let size = egui::vec2(shape.width as f32, shape.height as f32) * zoom;
+                    ui.painter_at(image_rect).image(
+                        texture.id(),
+                        egui::Rect::from_min_size(min, size),
+                        egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0)),
+                        egui::Color32::WHITE,
+                    );
+                }
+            }
         } else {
             ui.label("No frame available.");
         }
@@ -129,15 +276,17 @@ impl Receiver {
         if !self.ip_address.is_empty() {
             println!("Connecting to {}", self.ip_address);
             let ip = self.ip_address.clone();
+            let channel = self.channel.clone();
             let runtime = Arc::clone(&self.runtime);
 
             // Spawn a new async task to handle the connection
             let result = runtime.block_on(async {
-                connect_to_server(&ip).await
+                connect_to_server(&ip, channel).await
             });
 
             match result {
                 Ok((frame_rx, disconnect_handle)) => {
+                    self.reconnect_attempts = 0;
                     self.connected = true;
                     self.disconnect_handle = Some(disconnect_handle);
                     self.frame_receiver = Some(frame_rx);
@@ -150,12 +299,77 @@ impl Receiver {
         }
     }
 
+    // Start the next reconnect attempt when it is due, and collect its result
+    fn poll_reconnect(&mut self) {
+        let Some(reconnect) = &mut self.reconnect else {
+            return;
+        };
+
+        if let Some(pending) = &mut reconnect.pending {
+            let result = match pending.try_recv() {
+                Ok(result) => result,
+                Err(oneshot::error::TryRecvError::Empty) => return,
+                Err(oneshot::error::TryRecvError::Closed) => Err("Connection attempt was aborted".to_string()),
+            };
+            reconnect.pending = None;
+            match result {
+                Ok((frame_rx, disconnect_handle)) => {
+                    println!("Reconnected after {} attempt(s)", reconnect.attempt);
+                    self.reconnect_attempts = reconnect.attempt;
+                    self.reconnect = None;
+                    self.connected = true;
+                    self.disconnect_handle = Some(disconnect_handle);
+                    self.frame_receiver = Some(frame_rx);
+                    self.error_message = None;
+                }
+                Err(err) => {
+                    reconnect.retry_at = Instant::now() + Reconnect::delay(reconnect.attempt);
+                    reconnect.last_error = Some(format!("Error: {}", err));
+                }
+            }
+            return;
+        }
+
+        if Instant::now() >= reconnect.retry_at {
+            reconnect.attempt += 1;
+            let (result_tx, result_rx) = oneshot::channel();
+            let address = self.ip_address.clone();
+            let channel = self.channel.clone();
+            self.runtime.spawn(async move {
+                let _ = result_tx.send(connect_to_server(&address, channel).await);
+            });
+            reconnect.pending = Some(result_rx);
+        }
+    }
+
     fn handle_disconnect(&mut self) {
+        // Dropping a pending attempt's receiver makes its connection close once it completes
+        self.reconnect = None;
         if let Some(handle) = self.disconnect_handle.take() {
             self.runtime.block_on(handle.disconnect());
             println!("Disconnected");
         }
         self.connected = false;
         self.current_frame = None;
+        self.metadata = None;
+        self.is_paused = false;
+        self.channels.clear();
+        self.cursor = None;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_delay_doubles_up_to_the_limit() {
+        assert_eq!(Reconnect::delay(0), RECONNECT_FIRST_DELAY);
+        assert_eq!(Reconnect::delay(1), RECONNECT_FIRST_DELAY * 2);
+        assert_eq!(Reconnect::delay(3), RECONNECT_FIRST_DELAY * 8);
+        for attempt in [5, 6, 16, 17, 64, u32::MAX] {
+            assert_eq!(Reconnect::delay(attempt), RECONNECT_MAX_DELAY, "attempt {}", attempt);
+        }
+        let delays: Vec<Duration> = (0..40).map(Reconnect::delay).collect();
+        assert!(delays.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+}
@@ -1,50 +1,96 @@
-use scrap::{Capturer, Display};
+use std::fmt;
+use std::io;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::thread::JoinHandle;
 use tokio::sync::watch;
 use serde::{Deserialize, Serialize};
+use crate::cursor::Cursor;
+use crate::source::FrameSource;
 
-pub fn available_displays() -> Vec<String> {
-    let displays: Vec<String> = Display::all()
-        .iter()
-        .enumerate()
-        .map(|(index, _)| format!("Monitor {}", index + 1))
-        .collect();
-    displays
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Frame{
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height : u32
 }
+pub struct ScreenCapture {
+    pub rx: watch::Receiver<Frame>, // What the sink made of the newest frame
+    fps: Arc<AtomicU32>, // Read by the capture thread before every frame
+    running: Arc<AtomicBool>, // Cleared to ask the capture thread to exit
+    thread: Option<JoinHandle<()>>,
+    errors: mpsc::Receiver<CaptureError>, // Why the capture thread stopped, if it failed
+}
+
+// Takes every captured frame on the capture thread, so frames are processed and
+// sent at the capture rate whether or not the UI is drawing
+pub trait FrameSink: Send + 'static {
+    // Gets the frame with the pointer as it was then, returns the frame to preview
+    fn frame(&mut self, frame: Frame, cursor: Option<Cursor>) -> Frame;
 
-fn convert_bgra_to_rgba(frame: &[u8], width: u32, height: u32) -> Vec<u8> {
-    let h = height as usize;
-    let w = width as usize;
-    let stride = frame.len() / h; 
-    let mut rgba_data = Vec::with_capacity((width * height * 4) as usize);
+    // Gets the pointer between frames, every CURSOR_INTERVAL
+    fn cursor(&mut self, _cursor: Option<Cursor>) {}
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    NotFound(String), // The display or window doesn't exist (anymore)
+    PermissionDenied(String),
+    Unsupported(String), // Not available on this platform or session
+    Io(io::Error),
+    ThreadExited, // The capture thread ended without saying why
+}
 
-    for y in 0..h {
-        for x in 0..w {
-            let i = stride * y + 4 * x;
-            rgba_data.extend_from_slice(&[
-                frame[i + 2],
-                frame[i + 1],
-                frame[i],
-                255,
-            ]);
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::NotFound(reason) => write!(f, "Source not found: {}", reason),
+            CaptureError::PermissionDenied(reason) => write!(f, "Not allowed to capture: {}", reason),
+            CaptureError::Unsupported(reason) => write!(f, "Capture not supported: {}", reason),
+            CaptureError::Io(e) => write!(f, "Capture failed: {}", e),
+            CaptureError::ThreadExited => write!(f, "The capture thread stopped unexpectedly"),
         }
     }
+}
 
-    rgba_data
+impl From<io::Error> for CaptureError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => CaptureError::NotFound(e.to_string()),
+            io::ErrorKind::PermissionDenied => CaptureError::PermissionDenied(e.to_string()),
+            io::ErrorKind::Unsupported => CaptureError::Unsupported(e.to_string()),
+            _ => CaptureError::Io(e),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Frame{
-    pub data: Vec<u8>,
-    pub width: u32,
-    pub height : u32
+// Size frames are scaled down to before encoding
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Resolution {
+    Native,
+    FullHd,
+    Hd,
+    Sd,
+    Custom { width: u32, height: u32 },
 }
-pub struct ScreenCapture {
-    pub rx: watch::Receiver<Frame>,
+
+pub const DEFAULT_FPS: u32 = 30;
+pub const MAX_FPS: u32 = 60;
+// How often to ask again when the source has no new frame yet
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+// The pointer is sampled this often between frames, so it moves smoothly at low frame rates
+pub const CURSOR_INTERVAL: Duration = Duration::from_millis(16);
+
+// Paces a loop to a target rate. The next deadline is counted from the previous
+// one rather than from when the work finished, so slow frames don't lower the rate.
+pub struct FramePacer {
+    interval: Duration,
+    next_due: Instant,
 }
 
-#[derive(Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct CropValues {
     pub left: f32,
     pub right: f32,
@@ -52,58 +98,152 @@ pub struct CropValues {
     pub bottom: f32,
 }
 
+// How a privacy mask hides what is under it
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum MaskStyle {
+    Blur,
+    Pixelate,
+    Fill,
+}
+
+// A named part of the screen that is hidden before anything is encoded.
+// The area is given like a crop, as percentages cut from each side.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct MaskRegion {
+    pub name: String,
+    pub area: CropValues,
+    pub style: MaskStyle,
+}
+
+// Pixelate blocks and the blur radius, in pixels. Large enough that text can't be read.
+const MASK_BLOCK_SIZE: u32 = 16;
+const MASK_BLUR_RADIUS: u32 = 12;
+
 impl ScreenCapture {
-    // Constructor that initializes the capture thread and returns the receiver
-    pub fn new(index: usize) -> Result<Self, String> {
+    // Constructor that initializes the capture thread and returns the receiver.
+    // The source is built inside the thread, since platform capturers are not Send,
+    // but new waits for it so a source that can't start is reported right away.
+    pub fn new<S, F>(make_source: F, fps: u32, mut sink: impl FrameSink) -> Result<Self, CaptureError>
+    where
+        S: FrameSource,
+        F: FnOnce() -> std::io::Result<S> + Send + 'static,
+    {
         let (tx, rx) = watch::channel(Frame {
             data: vec![],
             width: 0,
             height: 0,
         });
 
-        thread::spawn(move || {
-            // Create a Capturer to capture the screen
-            let mut displays = Display::all().unwrap();
-            let display = displays.remove(index);
-            let mut capturer = Capturer::new(display).unwrap();
-            let width = capturer.width() as u32;
-            let height = capturer.height() as u32;
-
-            // Start capturing frames in a loop
-            let capture_interval = Duration::from_millis(30);
-            loop {
-                match capturer.frame() {
-                    Ok(frame) => {
-                        let rgba_frame = convert_bgra_to_rgba(&frame, width, height);
-                        let frame_data = Frame {
-                            data: rgba_frame.to_vec(),
-                            width,
-                            height,
-                        };
-
-                        if tx.send(frame_data).is_err() {
-                            eprintln!("Receiver has been dropped, stopping capture.");
-                            break;
+        let fps = Arc::new(AtomicU32::new(fps));
+        let thread_fps = Arc::clone(&fps);
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = Arc::clone(&running);
+        let (started_tx, started_rx) = mpsc::sync_channel(1);
+        let (error_tx, errors) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut source = match make_source() {
+                Ok(source) => {
+                    let _ = started_tx.send(Ok(()));
+                    source
+                }
+                Err(error) => {
+                    let _ = started_tx.send(Err(CaptureError::from(error)));
+                    return;
+                }
+            };
+
+            // Capture frames at the paced rate, and sample the pointer in between
+            let mut pacer = FramePacer::new(thread_fps.load(Ordering::Relaxed));
+            let mut next_cursor = Instant::now();
+            while thread_running.load(Ordering::Relaxed) {
+                let mut next_frame = pacer.next_due();
+                if Instant::now() >= next_frame {
+                    match source.next_frame() {
+                        Ok(frame_data) => {
+                            let pointer = source.cursor();
+                            if tx.send(sink.frame(frame_data, pointer)).is_err() {
+                                eprintln!("Receiver has been dropped, stopping capture.");
+                                break;
+                            }
+                            pacer.set_fps(thread_fps.load(Ordering::Relaxed));
+                            pacer.advance();
+                            next_frame = pacer.next_due();
+                            next_cursor = Instant::now() + CURSOR_INTERVAL;
                         }
-                    }
-                    Err(error) => {
-                        if error.kind() != std::io::ErrorKind::WouldBlock {
+                        Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                            // No new frame yet, try again shortly instead of losing a whole interval
+                            next_frame = Instant::now() + POLL_INTERVAL;
+                        }
+                        Err(error) => {
                             eprintln!("Error capturing frame: {:?}", error);
+                            let _ = error_tx.send(CaptureError::from(error));
                             break;
                         }
                     }
                 }
-
-                // Sleep for the capture interval (to control FPS)
-                thread::sleep(capture_interval);
+                if Instant::now() >= next_cursor {
+                    sink.cursor(source.cursor());
+                    next_cursor = Instant::now() + CURSOR_INTERVAL;
+                }
+                let wake = next_frame.min(next_cursor);
+                let now = Instant::now();
+                if wake > now {
+                    thread::sleep(wake - now);
+                }
             }
         });
 
-        Ok(ScreenCapture { rx })
+        // A thread that panicked while starting drops the sender without a word
+        match started_rx.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => {
+                let _ = thread.join();
+                return Err(error);
+            }
+            Err(_) => {
+                let _ = thread.join();
+                return Err(CaptureError::ThreadExited);
+            }
+        }
+
+        Ok(ScreenCapture { rx, fps, running, thread: Some(thread), errors })
     }
 
+    // Why capturing stopped, once it has. Frames are no longer produced after an error.
+    pub fn error(&self) -> Option<CaptureError> {
+        match self.errors.try_recv() {
+            Ok(error) => Some(error),
+            Err(mpsc::TryRecvError::Empty) => None,
+            // The thread is gone without reporting an error, unless it was asked to stop
+            Err(mpsc::TryRecvError::Disconnected) if self.running.load(Ordering::Relaxed) => {
+                Some(CaptureError::ThreadExited)
+            }
+            Err(mpsc::TryRecvError::Disconnected) => None,
+        }
+    }
+
+    // Stop the capture thread and wait for it to release the source.
+    // Takes at most one frame interval.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("Capture thread panicked");
+            }
+        }
+    }
+
+    // Change the capture rate, takes effect from the next frame
+    pub fn set_fps(&self, fps: u32) {
+        self.fps.store(fps, Ordering::Relaxed);
+    }
+
+    // The newest captured frame, or None if there is no frame since the last call
     pub fn receive_frame(&mut self) -> Option<Frame> {
-        let frame = self.rx.borrow();
+        if !self.rx.has_changed().unwrap_or(false) {
+            return None;
+        }
+        let frame = self.rx.borrow_and_update();
         if !frame.data.is_empty() {
             Some(frame.clone())
         } else {
@@ -112,10 +252,101 @@ impl ScreenCapture {
     }
 }
 
+impl Drop for ScreenCapture {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Frame {
+    // Whether the pixel data is exactly width * height RGBA pixels
+    pub fn is_valid(&self) -> bool {
+        (self.width as usize)
+            .checked_mul(self.height as usize)
+            .and_then(|pixels| pixels.checked_mul(4))
+            == Some(self.data.len())
+    }
+}
+
+impl FramePacer {
+    pub fn new(fps: u32) -> Self {
+        Self {
+            interval: Self::interval(fps),
+            next_due: Instant::now(),
+        }
+    }
+
+    pub fn set_fps(&mut self, fps: u32) {
+        self.interval = Self::interval(fps);
+    }
+
+    // When the next frame should be taken
+    pub fn next_due(&self) -> Instant {
+        self.next_due
+    }
+
+    // Move on to the next deadline, after a frame was taken
+    pub fn advance(&mut self) {
+        self.next_due += self.interval;
+        let now = Instant::now();
+        if self.next_due < now {
+            // Running late: start over from now rather than bursting to catch up
+            self.next_due = now;
+        }
+    }
+
+    fn interval(fps: u32) -> Duration {
+        Duration::from_secs(1) / fps.clamp(1, MAX_FPS)
+    }
+}
+
+impl Resolution {
+    pub const PRESETS: [Resolution; 4] = [Resolution::Native, Resolution::FullHd, Resolution::Hd, Resolution::Sd];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Resolution::Native => "Native",
+            Resolution::FullHd => "1080p",
+            Resolution::Hd => "720p",
+            Resolution::Sd => "480p",
+            Resolution::Custom { .. } => "Custom",
+        }
+    }
+
+    // Largest size within this resolution that keeps the frame's aspect ratio.
+    // Frames that already fit are left alone, never scaled up.
+    pub fn fit(&self, width: u32, height: u32) -> (u32, u32) {
+        let (max_width, max_height) = match *self {
+            Resolution::Native => return (width, height),
+            Resolution::FullHd => (1920, 1080),
+            Resolution::Hd => (1280, 720),
+            Resolution::Sd => (854, 480),
+            Resolution::Custom { width, height } => (width.max(1), height.max(1)),
+        };
+        if width <= max_width && height <= max_height {
+            return (width, height);
+        }
+        let scale = (max_width as f64 / width as f64).min(max_height as f64 / height as f64);
+        let fitted_width = ((width as f64 * scale).round() as u32).clamp(1, max_width);
+        let fitted_height = ((height as f64 * scale).round() as u32).clamp(1, max_height);
+        (fitted_width, fitted_height)
+    }
+}
+
 impl CropValues {
     pub fn new(left: f32, right: f32, top: f32, bottom: f32) -> Self {
         Self { left, right, top, bottom }
     }
+
+    // The kept region in pixels as (x, y, width, height), never smaller than one pixel
+    pub fn region(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let to_pixels = |percent: f32, size: u32| ((percent.clamp(0.0, 100.0) / 100.0) * size as f32).round() as u32;
+        let left = to_pixels(self.left, width).min(width.saturating_sub(1));
+        let top = to_pixels(self.top, height).min(height.saturating_sub(1));
+        let right = width.saturating_sub(to_pixels(self.right, width)).max(left + 1);
+        let bottom = height.saturating_sub(to_pixels(self.bottom, height)).max(top + 1);
+        (left, top, right - left, bottom - top)
+    }
 }
 
 pub fn crop(frame: &mut Frame, crop: CropValues) {
@@ -159,6 +390,45 @@ pub fn crop(frame: &mut Frame, crop: CropValues) {
     }
 }
 
+// Copy out only the region kept by the crop, so the margins are not sent at all
+pub fn crop_to_region(frame: &Frame, crop: &CropValues) -> Frame {
+    let (x, y, width, height) = crop.region(frame.width, frame.height);
+    let row_bytes = width as usize * 4;
+    let mut data = Vec::with_capacity(row_bytes * height as usize);
+    for row in y..y + height {
+        let start = (row as usize * frame.width as usize + x as usize) * 4;
+        data.extend_from_slice(&frame.data[start..start + row_bytes]);
+    }
+    Frame { data, width, height }
+}
+
+// Blend the pointer into the frame, clipped to its edges
+pub fn draw_cursor(frame: &mut Frame, cursor: &Cursor) {
+    let shape = &cursor.shape;
+    let left = cursor.x - shape.hot_x as i32;
+    let top = cursor.y - shape.hot_y as i32;
+    for row in 0..shape.height as i32 {
+        let y = top + row;
+        if y < 0 || y >= frame.height as i32 {
+            continue;
+        }
+        for column in 0..shape.width as i32 {
+            let x = left + column;
+            if x < 0 || x >= frame.width as i32 {
+                continue;
+            }
+            let source = (row as usize * shape.width as usize + column as usize) * 4;
+            let target = (y as usize * frame.width as usize + x as usize) * 4;
+            let alpha = shape.data[source + 3] as u32;
+            for channel in 0..3 {
+                let over = shape.data[source + channel] as u32;
+                let under = frame.data[target + channel] as u32;
+                frame.data[target + channel] = ((over * alpha + under * (255 - alpha)) / 255) as u8;
+            }
+        }
+    }
+}
+
 pub fn blank(frame: &mut Frame, is_blank: bool) {
     // Assuming the frame is in RGBA format (4 bytes per pixel)
     if is_blank {
@@ -166,4 +436,283 @@ pub fn blank(frame: &mut Frame, is_blank: bool) {
             chunk.copy_from_slice(&[255, 255, 255, 255]); // Fill with white (RGBA)
         }
     }
-}
\ No newline at end of file
+}
+
+impl MaskStyle {
+    pub const ALL: [MaskStyle; 3] = [MaskStyle::Blur, MaskStyle::Pixelate, MaskStyle::Fill];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaskStyle::Blur => "Blur",
+            MaskStyle::Pixelate => "Pixelate",
+            MaskStyle::Fill => "Fill",
+        }
+    }
+}
+
+// Hide the region's pixels in place. Only pixels inside the region are read.
+pub fn mask(frame: &mut Frame, region: &MaskRegion) {
+    let (x, y, width, height) = region.area.region(frame.width, frame.height);
+    match region.style {
+        MaskStyle::Fill => {
+            for row in y..y + height {
+                let start = (row as usize * frame.width as usize + x as usize) * 4;
+                for pixel in frame.data[start..start + width as usize * 4].chunks_exact_mut(4) {
+                    pixel.copy_from_slice(&[0, 0, 0, 255]);
+                }
+            }
+        }
+        MaskStyle::Pixelate => {
+            for block_y in (y..y + height).step_by(MASK_BLOCK_SIZE as usize) {
+                for block_x in (x..x + width).step_by(MASK_BLOCK_SIZE as usize) {
+                    let block_width = MASK_BLOCK_SIZE.min(x + width - block_x);
+                    let block_height = MASK_BLOCK_SIZE.min(y + height - block_y);
+                    let offset = |row: u32, column: u32| (row as usize * frame.width as usize + column as usize) * 4;
+                    let mut sum = [0u32; 3];
+                    for row in block_y..block_y + block_height {
+                        for column in block_x..block_x + block_width {
+                            let i = offset(row, column);
+                            for (total, value) in sum.iter_mut().zip(&frame.data[i..i + 3]) {
+                                *total += *value as u32;
+                            }
+                        }
+                    }
+                    let count = block_width * block_height;
+                    let average = sum.map(|total| (total / count) as u8);
+                    for row in block_y..block_y + block_height {
+                        for column in block_x..block_x + block_width {
+                            let i = offset(row, column);
+                            frame.data[i..i + 3].copy_from_slice(&average);
+                        }
+                    }
+                }
+            }
+        }
+        MaskStyle::Blur => {
+            // Three box blur passes each way come close to a gaussian blur
+            let mut pixels = crop_to_region(frame, &region.area);
+            for _ in 0..3 {
+                box_blur(&mut pixels, MASK_BLUR_RADIUS, true);
+                box_blur(&mut pixels, MASK_BLUR_RADIUS, false);
+            }
+            let row_bytes = width as usize * 4;
+            for row in 0..height as usize {
+                let start = ((y as usize + row) * frame.width as usize + x as usize) * 4;
+                frame.data[start..start + row_bytes].copy_from_slice(&pixels.data[row * row_bytes..(row + 1) * row_bytes]);
+            }
+        }
+    }
+}
+
+// Average every pixel with its neighbours along rows (horizontal) or columns,
+// using a running sum. Pixels past the edges repeat the edge pixel.
+fn box_blur(frame: &mut Frame, radius: u32, horizontal: bool) {
+    let (lines, length) = if horizontal { (frame.height, frame.width) } else { (frame.width, frame.height) };
+    let index = |line: u32, position: u32| {
+        let (x, y) = if horizontal { (position, line) } else { (line, position) };
+        (y as usize * frame.width as usize + x as usize) * 4
+    };
+    let radius = radius as i64;
+    let window = (2 * radius + 1) as u32;
+    let mut output = vec![0u8; length as usize * 3];
+    for line in 0..lines {
+        let at = |position: i64| index(line, position.clamp(0, length as i64 - 1) as u32);
+        let mut sum = [0u32; 3];
+        for position in -radius..=radius {
+            let i = at(position);
+            for (total, value) in sum.iter_mut().zip(&frame.data[i..i + 3]) {
+                *total += *value as u32;
+            }
+        }
+        for position in 0..length as i64 {
+            let averaged = &mut output[position as usize * 3..position as usize * 3 + 3];
+            for (value, total) in averaged.iter_mut().zip(&sum) {
+                *value = (total / window) as u8;
+            }
+            let (leaving, entering) = (at(position - radius), at(position + radius + 1));
+            for (channel, total) in sum.iter_mut().enumerate() {
+                *total = *total + frame.data[entering + channel] as u32 - frame.data[leaving + channel] as u32;
+            }
+        }
+        for position in 0..length {
+            let i = index(line, position);
+            frame.data[i..i + 3].copy_from_slice(&output[position as usize * 3..position as usize * 3 + 3]);
+        }
+    }
+}
+
+// Halve both dimensions, averaging each 2x2 block of pixels
+pub fn downscale(frame: &Frame) -> Frame {
+    let width = (frame.width / 2).max(1);
+    let height = (frame.height / 2).max(1);
+    let source_width = frame.width as usize;
+    let mut data = vec![0; width as usize * height as usize * 4];
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            // Clamp so a one pixel wide or tall frame reuses its only row or column
+            let x0 = (x * 2).min(source_width - 1);
+            let x1 = (x * 2 + 1).min(source_width - 1);
+            let y0 = (y * 2).min(frame.height as usize - 1);
+            let y1 = (y * 2 + 1).min(frame.height as usize - 1);
+            for channel in 0..4 {
+                let sum: u32 = [(x0, y0), (x1, y0), (x0, y1), (x1, y1)]
+                    .iter()
+                    .map(|(px, py)| frame.data[(py * source_width + px) * 4 + channel] as u32)
+                    .sum();
+                data[(y * width as usize + x) * 4 + channel] = (sum / 4) as u8;
+            }
+        }
+    }
+    Frame { data, width, height }
+}
+
+// Resample to the given size. Large reductions are first halved with a box filter,
+// since bilinear alone skips most source pixels and aliases badly.
+pub fn resize(frame: &Frame, width: u32, height: u32) -> Frame {
+    if frame.width == width && frame.height == height {
+        return frame.clone();
+    }
+    let mut halved: Option<Frame> = None;
+    loop {
+        let source = halved.as_ref().unwrap_or(frame);
+        if source.width / 2 < width || source.height / 2 < height {
+            break;
+        }
+        halved = Some(downscale(source));
+    }
+    bilinear(halved.as_ref().unwrap_or(frame), width, height)
+}
+
+fn bilinear(frame: &Frame, width: u32, height: u32) -> Frame {
+    let source_width = frame.width as usize;
+    let source_height = frame.height as usize;
+    let scale_x = frame.width as f32 / width as f32;
+    let scale_y = frame.height as f32 / height as f32;
+    let mut data = vec![0; width as usize * height as usize * 4];
+    for y in 0..height as usize {
+        // Sample at pixel centers
+        let source_y = ((y as f32 + 0.5) * scale_y - 0.5).clamp(0.0, (source_height - 1) as f32);
+        let y0 = source_y as usize;
+        let y1 = (y0 + 1).min(source_height - 1);
+        let weight_y = source_y - y0 as f32;
+        for x in 0..width as usize {
+            let source_x = ((x as f32 + 0.5) * scale_x - 0.5).clamp(0.0, (source_width - 1) as f32);
+            let x0 = source_x as usize;
+            let x1 = (x0 + 1).min(source_width - 1);
+            let weight_x = source_x - x0 as f32;
+            for channel in 0..4 {
+                let sample = |px: usize, py: usize| frame.data[(py * source_width + px) * 4 + channel] as f32;
+                let top = sample(x0, y0) + (sample(x1, y0) - sample(x0, y0)) * weight_x;
+                let bottom = sample(x0, y1) + (sample(x1, y1) - sample(x0, y1)) * weight_x;
+                data[(y * width as usize + x) * 4 + channel] = (top + (bottom - top) * weight_y).round() as u8;
+            }
+        }
+    }
+    Frame { data, width, height }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_crop_keeps_the_whole_frame() {
+        assert_eq!(CropValues::new(0.0, 0.0, 0.0, 0.0).region(640, 480), (0, 0, 640, 480));
+    }
+
+    #[test]
+    fn crop_is_taken_from_each_side() {
+        assert_eq!(CropValues::new(10.0, 20.0, 25.0, 50.0).region(100, 200), (10, 50, 70, 50));
+    }
+
+    #[test]
+    fn crop_never_goes_below_one_pixel() {
+        // Sides that meet or cross, and values outside 0-100
+        assert_eq!(CropValues::new(60.0, 60.0, 50.0, 50.0).region(100, 100), (60, 50, 1, 1));
+        assert_eq!(CropValues::new(100.0, 0.0, 100.0, 0.0).region(100, 100), (99, 99, 1, 1));
+        assert_eq!(CropValues::new(-20.0, 150.0, f32::NAN, 0.0).region(100, 100).2, 1);
+        assert_eq!(CropValues::new(50.0, 50.0, 50.0, 50.0).region(1, 1), (0, 0, 1, 1));
+    }
+
+    #[test]
+    fn native_and_smaller_frames_are_left_alone() {
+        assert_eq!(Resolution::Native.fit(7680, 4320), (7680, 4320));
+        assert_eq!(Resolution::Hd.fit(800, 600), (800, 600));
+        assert_eq!(Resolution::Hd.fit(1280, 720), (1280, 720));
+    }
+
+    #[test]
+    fn larger_frames_keep_their_aspect_ratio() {
+        assert_eq!(Resolution::FullHd.fit(3840, 2160), (1920, 1080));
+        // Limited by the height
+        assert_eq!(Resolution::Hd.fit(1000, 1000), (720, 720));
+        // Limited by the width
+        assert_eq!(Resolution::Sd.fit(3840, 1080), (854, 240));
+    }
+
+    #[test]
+    fn extreme_shapes_stay_within_bounds() {
+        assert_eq!(Resolution::Hd.fit(100_000, 1), (1280, 1));
+        assert_eq!(Resolution::Hd.fit(1, 100_000), (1, 720));
+        assert_eq!(Resolution::Custom { width: 0, height: 0 }.fit(640, 480), (1, 1));
+    }
+
+    // Every pixel different, so any blur or averaging shows
+    fn noisy_frame(width: u32, height: u32) -> Frame {
+        let data = (0..width * height * 4)
+            .map(|i| if i % 4 == 3 { 255 } else { (i.wrapping_mul(2_654_435_761) >> 13) as u8 })
+            .collect();
+        Frame { data, width, height }
+    }
+
+    #[test]
+    fn masks_only_change_pixels_inside_their_region() {
+        let areas = [
+            CropValues::new(20.0, 30.0, 25.0, 25.0),
+            CropValues::new(0.0, 0.0, 0.0, 0.0),
+            CropValues::new(0.0, 90.0, 80.0, 0.0),
+            CropValues::new(99.0, 0.0, 0.0, 99.0),
+        ];
+        for style in MaskStyle::ALL {
+            for area in &areas {
+                let original = noisy_frame(101, 67);
+                let mut masked = original.clone();
+                let region = MaskRegion { name: "test".to_string(), area: area.clone(), style };
+                mask(&mut masked, &region);
+
+                let (left, top, width, height) = area.region(original.width, original.height);
+                let mut changed_inside = 0;
+                for y in 0..original.height {
+                    for x in 0..original.width {
+                        let i = ((y * original.width + x) * 4) as usize;
+                        let inside = x >= left && x < left + width && y >= top && y < top + height;
+                        let same = original.data[i..i + 4] == masked.data[i..i + 4];
+                        if !inside {
+                            assert!(same, "{:?} {:?} changed {},{}", style, area, x, y);
+                        } else if !same {
+                            changed_inside += 1;
+                        }
+                    }
+                }
+                // A single pixel can't be blurred or averaged with anything
+                if width * height > 1 {
+                    assert!(changed_inside > 0, "{:?} {:?} left the region as it was", style, area);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fill_mask_leaves_nothing_of_the_original() {
+        let mut frame = noisy_frame(64, 48);
+        let area = CropValues::new(10.0, 10.0, 10.0, 10.0);
+        mask(&mut frame, &MaskRegion { name: "test".to_string(), area: area.clone(), style: MaskStyle::Fill });
+        let (left, top, width, height) = area.region(64, 48);
+        for y in top..top + height {
+            for x in left..left + width {
+                let i = ((y * 64 + x) * 4) as usize;
+                assert_eq!(frame.data[i..i + 4], [0, 0, 0, 255]);
+            }
+        }
+    }
+}
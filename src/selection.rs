@@ -0,0 +1,292 @@
+use eframe::egui::{self, Color32, CursorIcon, Pos2, Rect, Sense, Stroke, TextureHandle, Vec2};
+use crate::screen::CropValues;
+
+// Corner and edge handles are grabbed within this many points
+const HANDLE_RADIUS: f32 = 8.0;
+// Smallest selection, as a fraction of the frame
+const MIN_SIZE: f32 = 0.01;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AspectLock {
+    Free,
+    Wide,     // 16:9
+    Standard, // 4:3
+}
+
+impl AspectLock {
+    pub const ALL: [AspectLock; 3] = [AspectLock::Free, AspectLock::Wide, AspectLock::Standard];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AspectLock::Free => "Free",
+            AspectLock::Wide => "16:9",
+            AspectLock::Standard => "4:3",
+        }
+    }
+
+    fn ratio(&self) -> Option<f32> {
+        match self {
+            AspectLock::Free => None,
+            AspectLock::Wide => Some(16.0 / 9.0),
+            AspectLock::Standard => Some(4.0 / 3.0),
+        }
+    }
+}
+
+// What the current drag is changing. Positions are normalized to the frame, 0 to 1.
+#[derive(Clone, Copy)]
+enum Drag {
+    Draw { anchor: Pos2 },   // Drawing a new rectangle from this corner
+    Move { grab: Vec2 },     // Offset from the pointer to the rectangle's top-left corner
+    Resize { left: bool, right: bool, top: bool, bottom: bool },
+}
+
+// Rectangle selection drawn over the caster preview, editing the same CropValues as the sliders
+pub struct CropSelector {
+    pub aspect: AspectLock,
+    drag: Option<Drag>,
+}
+
+impl CropSelector {
+    pub fn new() -> Self {
+        Self { aspect: AspectLock::Free, drag: None }
+    }
+
+    // Shrink the selection around its center to match the aspect lock
+    pub fn apply_aspect(&self, crop: &mut CropValues, frame_width: u32, frame_height: u32) {
+        let Some(ratio) = self.normalized_ratio(frame_width, frame_height) else {
+            return;
+        };
+        let rect = to_rect(crop);
+        if rect.width() < MIN_SIZE || rect.height() < MIN_SIZE {
+            return;
+        }
+        let size = if rect.width() / rect.height() > ratio {
+            Vec2::new(rect.height() * ratio, rect.height())
+        } else {
+            Vec2::new(rect.width(), rect.width() / ratio)
+        };
+        set_rect(crop, Rect::from_center_size(rect.center(), size));
+    }
+
+    // Draw the frame with the selection on top, and let the user drag it
+    pub fn show(&mut self, ui: &mut egui::Ui, texture: &TextureHandle, size: Vec2, frame_width: u32, frame_height: u32, crop: &mut CropValues) {
+        let (image_rect, response) = ui.allocate_exact_size(size, Sense::drag());
+        egui::Image::new(texture).paint_at(ui, image_rect);
+
+        let to_normalized = |pos: Pos2| {
+            let pos = (pos - image_rect.min) / image_rect.size();
+            Pos2::new(pos.x.clamp(0.0, 1.0), pos.y.clamp(0.0, 1.0))
+        };
+        let to_screen = |rect: Rect| {
+            Rect::from_min_max(
+                image_rect.min + rect.min.to_vec2() * image_rect.size(),
+                image_rect.min + rect.max.to_vec2() * image_rect.size(),
+            )
+        };
+        let ratio = self.normalized_ratio(frame_width, frame_height);
+        let selection = to_screen(to_rect(crop));
+
+        if let Some(pointer) = response.hover_pos() {
+            let (left, right, top, bottom) = grabbed_edges(selection, pointer, ratio.is_some());
+            let icon = match (left || right, top || bottom) {
+                (true, true) if (left && top) || (right && bottom) => CursorIcon::ResizeNwSe,
+                (true, true) => CursorIcon::ResizeNeSw,
+                (true, false) => CursorIcon::ResizeHorizontal,
+                (false, true) => CursorIcon::ResizeVertical,
+                _ if selection.contains(pointer) => CursorIcon::Grab,
+                _ => CursorIcon::Crosshair,
+            };
+            ui.ctx().set_cursor_icon(icon);
+        }
+
+        if response.drag_started() {
+            if let Some(pointer) = response.interact_pointer_pos() {
+                let (left, right, top, bottom) = grabbed_edges(selection, pointer, ratio.is_some());
+                self.drag = Some(if left || right || top || bottom {
+                    Drag::Resize { left, right, top, bottom }
+                } else if selection.contains(pointer) {
+                    Drag::Move { grab: to_rect(crop).min - to_normalized(pointer) }
+                } else {
+                    Drag::Draw { anchor: to_normalized(pointer) }
+                });
+            }
+        }
+
+        if let (Some(drag), Some(pointer)) = (self.drag, response.interact_pointer_pos()) {
+            let pointer = to_normalized(pointer);
+            let rect = to_rect(crop);
+            let new_rect = match drag {
+                Drag::Draw { anchor } => corner_drag(anchor, pointer, ratio),
+                Drag::Move { grab } => {
+                    let min = (pointer + grab).clamp(Pos2::ZERO, Pos2::new(1.0 - rect.width(), 1.0 - rect.height()));
+                    Rect::from_min_size(min, rect.size())
+                }
+                Drag::Resize { left, top, .. } if ratio.is_some() => {
+                    // With a locked ratio only corners are grabbed, the opposite corner stays put
+                    let anchor = Pos2::new(
+                        if left { rect.max.x } else { rect.min.x },
+                        if top { rect.max.y } else { rect.min.y },
+                    );
+                    corner_drag(anchor, pointer, ratio)
+                }
+                Drag::Resize { left, right, top, bottom } => {
+                    let mut rect = rect;
+                    if left { rect.min.x = pointer.x.min(rect.max.x - MIN_SIZE); }
+                    if right { rect.max.x = pointer.x.max(rect.min.x + MIN_SIZE); }
+                    if top { rect.min.y = pointer.y.min(rect.max.y - MIN_SIZE); }
+                    if bottom { rect.max.y = pointer.y.max(rect.min.y + MIN_SIZE); }
+                    rect
+                }
+            };
+            if new_rect.width() >= MIN_SIZE && new_rect.height() >= MIN_SIZE {
+                set_rect(crop, new_rect);
+            }
+        }
+        if response.drag_stopped() {
+            self.drag = None;
+        }
+
+        // Dim what is cropped away, then outline the selection with its handles
+        let selection = to_screen(to_rect(crop));
+        let painter = ui.painter_at(image_rect);
+        let shade = Color32::from_black_alpha(140);
+        painter.rect_filled(Rect::from_min_max(image_rect.min, Pos2::new(image_rect.max.x, selection.min.y)), 0.0, shade);
+        painter.rect_filled(Rect::from_min_max(Pos2::new(image_rect.min.x, selection.max.y), image_rect.max), 0.0, shade);
+        painter.rect_filled(Rect::from_min_max(Pos2::new(image_rect.min.x, selection.min.y), Pos2::new(selection.min.x, selection.max.y)), 0.0, shade);
+        painter.rect_filled(Rect::from_min_max(Pos2::new(selection.max.x, selection.min.y), Pos2::new(image_rect.max.x, selection.max.y)), 0.0, shade);
+        painter.rect_stroke(selection, 0.0, Stroke::new(2.0, Color32::WHITE));
+
+        let mut handles = vec![selection.left_top(), selection.right_top(), selection.left_bottom(), selection.right_bottom()];
+        if ratio.is_none() {
+            handles.extend([selection.center_top(), selection.center_bottom(), selection.left_center(), selection.right_center()]);
+        }
+        for handle in handles {
+            painter.rect_filled(Rect::from_center_size(handle, Vec2::splat(HANDLE_RADIUS)), 1.0, Color32::WHITE);
+        }
+    }
+
+    // The aspect ratio in normalized units, which differ from pixels unless the frame is square
+    fn normalized_ratio(&self, frame_width: u32, frame_height: u32) -> Option<f32> {
+        self.aspect.ratio().map(|ratio| ratio * frame_height.max(1) as f32 / frame_width.max(1) as f32)
+    }
+}
+
+// The selection as a normalized rectangle
+fn to_rect(crop: &CropValues) -> Rect {
+    let rect = Rect::from_two_pos(
+        Pos2::new(crop.left / 100.0, crop.top / 100.0),
+        Pos2::new(1.0 - crop.right / 100.0, 1.0 - crop.bottom / 100.0),
+    );
+    rect.intersect(Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)))
+}
+
+fn set_rect(crop: &mut CropValues, rect: Rect) {
+    crop.left = (rect.min.x * 100.0).clamp(0.0, 100.0);
+    crop.top = (rect.min.y * 100.0).clamp(0.0, 100.0);
+    crop.right = ((1.0 - rect.max.x) * 100.0).clamp(0.0, 100.0);
+    crop.bottom = ((1.0 - rect.max.y) * 100.0).clamp(0.0, 100.0);
+}
+
+// Which edges of the selection are under the pointer. Edge midpoints only count without an aspect lock.
+fn grabbed_edges(selection: Rect, pointer: Pos2, corners_only: bool) -> (bool, bool, bool, bool) {
+    let near = |a: f32, b: f32| (a - b).abs() <= HANDLE_RADIUS;
+    let within_x = pointer.x >= selection.min.x - HANDLE_RADIUS && pointer.x <= selection.max.x + HANDLE_RADIUS;
+    let within_y = pointer.y >= selection.min.y - HANDLE_RADIUS && pointer.y <= selection.max.y + HANDLE_RADIUS;
+    let left = within_y && near(pointer.x, selection.min.x);
+    let right = within_y && !left && near(pointer.x, selection.max.x);
+    let top = within_x && near(pointer.y, selection.min.y);
+    let bottom = within_x && !top && near(pointer.y, selection.max.y);
+    if corners_only && !((left || right) && (top || bottom)) {
+        return (false, false, false, false);
+    }
+    (left, right, top, bottom)
+}
+
+// Rectangle spanned from a fixed corner to the pointer, kept at the ratio if there is one
+// and shrunk to stay inside the frame
+fn corner_drag(anchor: Pos2, pointer: Pos2, ratio: Option<f32>) -> Rect {
+    let Some(ratio) = ratio else {
+        return Rect::from_two_pos(anchor, pointer);
+    };
+    let direction = Vec2::new(
+        if pointer.x >= anchor.x { 1.0 } else { -1.0 },
+        if pointer.y >= anchor.y { 1.0 } else { -1.0 },
+    );
+    // Room left between the anchor and the frame border in the drag direction
+    let room_x = if direction.x > 0.0 { 1.0 - anchor.x } else { anchor.x };
+    let room_y = if direction.y > 0.0 { 1.0 - anchor.y } else { anchor.y };
+
+    let mut width = (pointer.x - anchor.x).abs().max((pointer.y - anchor.y).abs() * ratio);
+    width = width.min(room_x).min(room_y * ratio);
+    let height = width / ratio;
+    Rect::from_two_pos(anchor, anchor + Vec2::new(width, height) * direction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inside_frame(rect: Rect) -> bool {
+        let eps = 1e-5;
+        rect.min.x >= -eps && rect.min.y >= -eps && rect.max.x <= 1.0 + eps && rect.max.y <= 1.0 + eps
+    }
+
+    #[test]
+    fn free_corner_drag_follows_the_pointer() {
+        let rect = corner_drag(Pos2::new(0.6, 0.7), Pos2::new(0.2, 0.1), None);
+        assert_eq!(rect, Rect::from_min_max(Pos2::new(0.2, 0.1), Pos2::new(0.6, 0.7)));
+    }
+
+    #[test]
+    fn locked_corner_drag_keeps_the_ratio_inside_the_frame() {
+        let ratio = 1.5;
+        let points = [0.0, 0.1, 0.5, 0.9, 1.0];
+        for &ax in &points {
+            for &ay in &points {
+                for &px in &points {
+                    for &py in &points {
+                        let (anchor, pointer) = (Pos2::new(ax, ay), Pos2::new(px, py));
+                        let rect = corner_drag(anchor, pointer, Some(ratio));
+                        assert!(inside_frame(rect), "{:?} to {:?} gave {:?}", anchor, pointer, rect);
+                        if rect.height() > 0.0 {
+                            assert!((rect.width() / rect.height() - ratio).abs() < 1e-3, "{:?} to {:?} gave {:?}", anchor, pointer, rect);
+                        }
+                        // The anchor stays a corner of the rectangle
+                        assert!(rect.min.x == anchor.x || rect.max.x == anchor.x);
+                        assert!(rect.min.y == anchor.y || rect.max.y == anchor.y);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn apply_aspect_gives_the_pixel_ratio() {
+        for (aspect, ratio) in [(AspectLock::Wide, 16.0 / 9.0), (AspectLock::Standard, 4.0 / 3.0)] {
+            for (frame_width, frame_height) in [(1920, 1080), (1000, 1000), (1080, 1920), (640, 480)] {
+                for start in [CropValues::new(0.0, 0.0, 0.0, 0.0), CropValues::new(10.0, 40.0, 5.0, 20.0)] {
+                    let mut crop = start.clone();
+                    let selector = CropSelector { aspect, drag: None };
+                    selector.apply_aspect(&mut crop, frame_width, frame_height);
+                    for value in [crop.left, crop.right, crop.top, crop.bottom] {
+                        assert!((0.0..=100.0).contains(&value), "{:?}", crop);
+                    }
+                    let (_, _, width, height) = crop.region(frame_width, frame_height);
+                    let actual = width as f32 / height as f32;
+                    assert!((actual - ratio).abs() < 0.02, "{}x{} from {:?} gave {}x{}", frame_width, frame_height, start, width, height);
+                    // Only ever shrinks the selection
+                    let (_, _, start_width, start_height) = start.region(frame_width, frame_height);
+                    assert!(width <= start_width && height <= start_height);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn free_aspect_leaves_the_crop_alone() {
+        let mut crop = CropValues::new(10.0, 40.0, 5.0, 20.0);
+        CropSelector::new().apply_aspect(&mut crop, 1920, 1080);
+        assert_eq!(crop, CropValues::new(10.0, 40.0, 5.0, 20.0));
+    }
+}
@@ -1,150 +1,767 @@
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::{broadcast::{self, error::RecvError}, Mutex};
 use tokio::io::{AsyncWriteExt};
 use tokio::runtime::Runtime;
-use std::sync::{Arc, atomic::{AtomicUsize,AtomicBool,Ordering}};
-use std::net::SocketAddr;
-use std::collections::{HashMap};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use std::sync::{Arc, atomic::{AtomicU64,AtomicUsize,AtomicBool,Ordering}};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::collections::{HashMap, VecDeque};
 use bytes::{Bytes};
 use std::time::{Instant,Duration};
-use crate::screen::Frame;
-use bincode;
+use crate::screen::{downscale, Frame};
+use crate::codec::{Codec, FrameEncoder, H264Settings, DEFAULT_QUALITY};
+use crate::protocol::{self, CursorState, Hello, Message, StreamMetadata, DEFAULT_PORT, PING_INTERVAL};
+use crate::cursor::CursorShape;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// A client with this many frames of its tier waiting is moved to the reduced tier
+const DEGRADE_QUEUE_DEPTH: u64 = 32;
+// A reduced client goes back to full once it has kept up for this long
+const UPGRADE_AFTER: Duration = Duration::from_secs(15);
+// Window over which the send rate is measured
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(2);
+const TIER_COUNT: usize = 2;
+// An unchanged screen is still sent this often, so receivers know it is alive
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// A tier's history beyond this size asks for a keyframe so it can start over
+const MAX_HISTORY_BYTES: usize = 64 * 1024 * 1024;
+
+// Each tier is encoded separately, and every client is served from one of them
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Tier {
+    #[default]
+    Full,
+    Reduced, // Half resolution, half frame rate, lower quality
+}
+
+// A message ready to be written to the sockets
+#[derive(Clone)]
+struct Packet {
+    codec: Option<Codec>, // Set for frames, so clients that can't decode them are turned away
+    keyframe: bool,
+    pixels: u64, // Frame width times height, 0 for other messages
+    tier: Option<Tier>, // Only sent to clients in this tier, None goes to everyone
+    last: bool, // The connection is closed once this is written
+    data: Bytes,
+}
+
+// How a single client is keeping up with the stream
+#[derive(Clone, Default, Debug)]
+pub struct ClientStats {
+    pub channel: String,
+    pub tier: Tier,
+    pub bytes_sent: u64,
+    pub throughput_kbps: u32, // Send rate over the last window
+    pub queue_depth: u64, // Frames of its tier waiting for this client
+    pub skipped: u64, // Messages dropped because the client fell behind
+    pub resyncs: u64, // Times it had to wait for a keyframe after falling behind
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+// Where the server listens for receivers
+#[derive(Clone, PartialEq, Debug)]
+pub struct ServerConfig {
+    pub address: String, // Empty means every interface of the selected IP version
+    pub ip_version: IpVersion,
+    pub port: u16,
+}
+
+// What a client joining a tier needs to show the current image right away
+#[derive(Default)]
+struct TierHistory {
+    metadata: Option<StreamMetadata>,
+    metadata_packet: Option<Packet>,
+    frames: Vec<Packet>, // The last keyframe and every frame sent since
+    bytes: usize,
+}
+
+// The last pointer messages, so joining clients see the pointer before it next moves
+#[derive(Default)]
+struct CursorHistory {
+    shape: Option<(CursorShape, Packet)>,
+    state: Option<(CursorState, Packet)>,
+}
+
+// The part of a channel that its client tasks share
+struct ChannelShared {
+    name: String,
+    sender: broadcast::Sender<Packet>,
+    keyframe_requested: [AtomicBool; TIER_COUNT], // Set when a client joins a tier mid-stream
+    history: std::sync::Mutex<[TierHistory; TIER_COUNT]>, // Replayed to every client on join
+    tier_clients: [AtomicUsize; TIER_COUNT], // How many clients each tier serves
+    frames_sent: [AtomicU64; TIER_COUNT], // Frame messages each tier has broadcast, to tell how far behind a client is
+    bytes_sent: [AtomicU64; TIER_COUNT], // Their size, to compare with what a client manages to write
+    cursor: std::sync::Mutex<CursorHistory>,
+}
+
+// One named stream, fed by one source. Receivers pick a channel when they connect.
+struct Channel {
+    shared: Arc<ChannelShared>,
+    encoders: [FrameEncoder; TIER_COUNT],
+    tick: u64, // Frames sent, the reduced tier only gets every other one
+    last_sent: [Option<Instant>; TIER_COUNT], // When each tier last sent a frame
+    blanked: bool, // The history was cleared for the current blank
+}
+
+// Sends frames to one channel. Handed to the capture thread of its source,
+// so frames go out at the capture rate whatever the UI is doing.
+#[derive(Clone)]
+pub struct ChannelSender {
+    name: String,
+    channel: Arc<std::sync::Mutex<Channel>>,
+    priority: Arc<AtomicBool>, // Set while the server disconnects everyone, frames are dropped
+}
+
+// Shared state handed to each client task
+struct ClientContext {
+    sockets: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>>,
+    client_count: Arc<AtomicUsize>,
+    stats: Arc<std::sync::Mutex<HashMap<SocketAddr, ClientStats>>>,
+    channels: Arc<std::sync::Mutex<Vec<Arc<ChannelShared>>>>,
+}
 
 // Define a struct to manage the server state
 pub struct StreamServer {
     sockets: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>>, // Updated type
-    sender: broadcast::Sender<Bytes>,                               // Broadcast channel
     runtime: Arc<Runtime>,
     client_count: Arc<AtomicUsize>,
-    time: Instant,
-    priority: AtomicBool,
+    priority: Arc<AtomicBool>,
+    channels: Vec<ChannelSender>,
+    directory: Arc<std::sync::Mutex<Vec<Arc<ChannelShared>>>>, // The channels, as seen by client tasks
+    accept_task: Option<JoinHandle<()>>,
+    stats: Arc<std::sync::Mutex<HashMap<SocketAddr, ClientStats>>>,
+    codec: Codec,
+    quality: u8,
+    h264_settings: H264Settings,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: String::new(),
+            ip_version: IpVersion::V4,
+            port: DEFAULT_PORT,
+        }
+    }
+}
+
+impl ServerConfig {
+    // The address to bind, checking that it matches the selected IP version
+    pub fn socket_addr(&self) -> Result<SocketAddr, String> {
+        let address = self.address.trim();
+        let ip = if address.is_empty() {
+            match self.ip_version {
+                IpVersion::V4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpVersion::V6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            }
+        } else {
+            let ip: IpAddr = address
+                .parse()
+                .map_err(|_| format!("Invalid listen address: {}", address))?;
+            if ip.is_ipv4() != (self.ip_version == IpVersion::V4) {
+                return Err(format!("{} is not an {} address", ip, self.ip_version.name()));
+            }
+            ip
+        };
+        Ok(SocketAddr::new(ip, self.port))
+    }
+}
+
+impl ChannelShared {
+    // Subscribe to the broadcast, along with the messages that bring a client of
+    // the tier up to the current image, and the tier's frame count at that point.
+    // The history lock keeps the three in step.
+    fn join(&self, tier: Tier) -> (broadcast::Receiver<Packet>, VecDeque<Packet>, u64) {
+        let history = self.history.lock().unwrap();
+        let history = &history[tier.index()];
+        let receiver = self.sender.subscribe();
+        let frames_sent = self.frames_sent[tier.index()].load(Ordering::SeqCst);
+        let mut pending: VecDeque<Packet> = history.metadata_packet.iter().chain(&history.frames).cloned().collect();
+        // Pointer messages only carry state, seeing one twice does no harm
+        let cursor = self.cursor.lock().unwrap();
+        let shape = cursor.shape.as_ref().map(|(_, packet)| packet);
+        let state = cursor.state.as_ref().map(|(_, packet)| packet);
+        pending.extend(shape.into_iter().chain(state).cloned());
+        (receiver, pending, frames_sent)
+    }
+
+    // Forget the frames sent so far and start every tier over with a keyframe
+    fn reset(&self) {
+        let mut history = self.history.lock().unwrap();
+        for (tier, requested) in history.iter_mut().zip(&self.keyframe_requested) {
+            tier.frames.clear();
+            tier.bytes = 0;
+            requested.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Tier {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tier::Full => "Full",
+            Tier::Reduced => "Reduced",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Channel {
+    fn new(name: String) -> Self {
+        let (sender, _) = broadcast::channel(2048);
+        Self {
+            shared: Arc::new(ChannelShared {
+                name,
+                sender,
+                keyframe_requested: [AtomicBool::new(false), AtomicBool::new(false)],
+                history: std::sync::Mutex::new(Default::default()),
+                tier_clients: [AtomicUsize::new(0), AtomicUsize::new(0)],
+                frames_sent: [AtomicU64::new(0), AtomicU64::new(0)],
+                bytes_sent: [AtomicU64::new(0), AtomicU64::new(0)],
+                cursor: std::sync::Mutex::new(CursorHistory::default()),
+            }),
+            encoders: [FrameEncoder::new(), FrameEncoder::new()],
+            tick: 0,
+            last_sent: [None, None],
+            blanked: false,
+        }
+    }
+
+    fn send(&self, message: &Message) {
+        if let Some(packet) = StreamServer::packet(message) {
+            let _ = self.shared.sender.send(packet);
+        }
+    }
+
+    // Encode a frame for one tier and send it, announcing size or codec changes first
+    fn broadcast_tier(&mut self, tier: Tier, frame: &Frame) {
+        let shared = &self.shared;
+        let encoder = &mut self.encoders[tier.index()];
+        // A static screen costs nothing until the next refresh, unless someone needs a keyframe
+        let keyframe_requested = shared.keyframe_requested[tier.index()].swap(false, Ordering::SeqCst);
+        let refresh_due = self.last_sent[tier.index()].is_none_or(|sent| sent.elapsed() >= REFRESH_INTERVAL);
+        if !keyframe_requested && !refresh_due && encoder.is_unchanged(frame) {
+            return;
+        }
+        if keyframe_requested {
+            encoder.request_keyframe();
+        }
+        let encoded = match encoder.encode(frame) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                eprintln!("Failed to encode frame: {}", e);
+                return;
+            }
+        };
+
+        // Record and send under the history lock, so joining clients see each message exactly once
+        let mut history = shared.history.lock().unwrap();
+        let history = &mut history[tier.index()];
+
+        // Tell receivers before the first frame with a new size or codec
+        let metadata = StreamMetadata {
+            width: encoded.width,
+            height: encoded.height,
+            codec: encoded.codec,
+        };
+        if history.metadata.as_ref() != Some(&metadata) {
+            history.metadata_packet = StreamServer::tier_packet(tier, &Message::Metadata(metadata.clone()));
+            history.metadata = Some(metadata);
+            if let Some(packet) = &history.metadata_packet {
+                let _ = shared.sender.send(packet.clone());
+            }
+        }
+
+        if encoded.keyframe {
+            history.frames.clear();
+            history.bytes = 0;
+        }
+        if let Some(packet) = StreamServer::tier_packet(tier, &Message::Frame(encoded)) {
+            history.bytes += packet.data.len();
+            history.frames.push(packet.clone());
+            shared.frames_sent[tier.index()].fetch_add(1, Ordering::SeqCst);
+            shared.bytes_sent[tier.index()].fetch_add(packet.data.len() as u64, Ordering::SeqCst);
+            let _ = shared.sender.send(packet);
+        }
+        if history.bytes > MAX_HISTORY_BYTES {
+            shared.keyframe_requested[tier.index()].store(true, Ordering::SeqCst);
+        }
+        self.last_sent[tier.index()] = Some(Instant::now());
+    }
+
+    fn apply_settings(&mut self, codec: Codec, quality: u8, h264_settings: H264Settings) {
+        let [full, reduced] = &mut self.encoders;
+        full.set_codec(codec);
+        full.set_quality(quality);
+        full.set_h264_settings(h264_settings);
+        // The reduced tier gets half the quality and bitrate
+        reduced.set_codec(codec);
+        reduced.set_quality(quality / 2);
+        reduced.set_h264_settings(H264Settings {
+            bitrate_kbps: (h264_settings.bitrate_kbps / 2).max(1),
+            ..h264_settings
+        });
+    }
+}
+
+impl ChannelSender {
+    // The channel's unique name, as receivers see it
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Start the channel over from its next frame, see StreamServer::reset_channel
+    pub fn reset(&self) {
+        self.channel.lock().unwrap().shared.reset();
+    }
+
+    // Broadcast a frame to the clients of the channel. Called once per captured
+    // frame, so the capture rate is also the send rate.
+    pub fn broadcast_frame(&self, frame: Frame, is_streaming: bool, is_blank: bool) {
+        if self.priority.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut channel = self.channel.lock().unwrap();
+        // Clients joining during a blank must not be replayed what it hides
+        if is_blank && !channel.blanked {
+            channel.shared.reset();
+        }
+        channel.blanked = is_blank;
+        if !is_streaming {
+            channel.send(&Message::Paused);
+        }
+        else if is_blank {
+            // Receivers blank their own screen, no need to send white pixels
+            channel.send(&Message::Blanked);
+        }
+        else {
+            channel.broadcast_tier(Tier::Full, &frame);
+            // Only spend time on the reduced tier while someone needs it
+            let reduced_clients = channel.shared.tier_clients[Tier::Reduced.index()].load(Ordering::SeqCst);
+            if reduced_clients > 0 && channel.tick.is_multiple_of(2) {
+                channel.broadcast_tier(Tier::Reduced, &downscale(&frame));
+            }
+            channel.tick += 1;
+        }
+    }
+
+    // Send the pointer to the channel's clients, only what changed since last time
+    pub fn broadcast_cursor(&self, state: CursorState, shape: Option<&CursorShape>) {
+        let channel = self.channel.lock().unwrap();
+        let shared = &channel.shared;
+        let mut cursor = shared.cursor.lock().unwrap();
+        if let Some(shape) = shape {
+            if cursor.shape.as_ref().map(|(sent, _)| sent) != Some(shape) {
+                if let Some(packet) = StreamServer::packet(&Message::CursorShape(shape.clone())) {
+                    let _ = shared.sender.send(packet.clone());
+                    cursor.shape = Some((shape.clone(), packet));
+                }
+            }
+        }
+        if cursor.state.as_ref().map(|(sent, _)| sent) != Some(&state) {
+            if let Some(packet) = StreamServer::packet(&Message::Cursor(state.clone())) {
+                let _ = shared.sender.send(packet.clone());
+                cursor.state = Some((state, packet));
+            }
+        }
+    }
+}
+
+impl IpVersion {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IpVersion::V4 => "IPv4",
+            IpVersion::V6 => "IPv6",
+        }
+    }
 }
 
 impl StreamServer {
-    // Create a new server instance
+    // Create a new server instance. It accepts clients once listen() succeeds.
     pub fn new() -> Self {
         // Create a Tokio runtime
         let runtime = Arc::new(Runtime::new().unwrap());
-        let (sender, _) = broadcast::channel(2048); // Buffer size of 256 messages
-        let sockets = Arc::new(Mutex::new(HashMap::new()));
-        let client_count = Arc::new(AtomicUsize::new(0));
-
-        let server = Self {
-            sockets: Arc::clone(&sockets),
-            sender: sender.clone(),
-            runtime: Arc::clone(&runtime),
-            client_count: Arc::clone(&client_count),
-            time: Instant::now(),
-            priority: AtomicBool::new(false),
-        };
 
-        // Use the runtime to spawn a task that starts the server
-        let runtime_clone = Arc::clone(&runtime);
-        let sockets_clone = Arc::clone(&sockets);
-        let client_count_clone = Arc::clone(&client_count);
+        Self {
+            sockets: Arc::new(Mutex::new(HashMap::new())),
+            runtime,
+            client_count: Arc::new(AtomicUsize::new(0)),
+            priority: Arc::new(AtomicBool::new(false)),
+            channels: Vec::new(),
+            directory: Arc::new(std::sync::Mutex::new(Vec::new())),
+            accept_task: None,
+            stats: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            codec: Codec::Raw,
+            quality: DEFAULT_QUALITY,
+            h264_settings: H264Settings::default(),
+        }
+    }
 
-        runtime.spawn(async move {
-            let listener = match TcpListener::bind(("0.0.0.0", 9041)).await {
-                Ok(listener) => listener,
-                Err(_) => {
-                    return; // Exit the task if binding fails
-                }
-            };
-            println!("Server started on port 9041");
+    // (Re)start listening with the given config. Clients that are already
+    // connected keep their sessions. Returns the address actually bound.
+    pub fn listen(&mut self, config: &ServerConfig) -> Result<SocketAddr, String> {
+        let addr = config.socket_addr()?;
+
+        // Release the previous listener first, it may hold the same port
+        if let Some(task) = self.accept_task.take() {
+            task.abort();
+            let _ = self.runtime.block_on(task);
+        }
+
+        let listener = self
+            .runtime
+            .block_on(TcpListener::bind(addr))
+            .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+        let local_addr = listener.local_addr().unwrap_or(addr);
+        println!("Server started on {}", local_addr);
 
+        // Use the runtime to spawn a task that accepts clients
+        let runtime_clone = Arc::clone(&self.runtime);
+        let sockets = Arc::clone(&self.sockets);
+        let client_count = Arc::clone(&self.client_count);
+        let stats = Arc::clone(&self.stats);
+        let channels = Arc::clone(&self.directory);
+
+        self.accept_task = Some(self.runtime.spawn(async move {
             loop {
                 if let Ok((socket, addr)) = listener.accept().await {
                     println!("Client connected: {}", addr);
-                    let socket_arc = Arc::new(Mutex::new(socket));
-
-                    // Add the new socket to the sockets map
-                    sockets_clone.lock().await.insert(addr, Arc::clone(&socket_arc));
-                    client_count_clone.fetch_add(1,Ordering::SeqCst);
 
-                    let sender = sender.clone();
-                    let sockets = Arc::clone(&sockets_clone);
-                    let client_count = Arc::clone(&client_count_clone);
+                    let client = ClientContext {
+                        sockets: Arc::clone(&sockets),
+                        client_count: Arc::clone(&client_count),
+                        stats: Arc::clone(&stats),
+                        channels: Arc::clone(&channels),
+                    };
 
                     // Spawn a task to handle the client
                     runtime_clone.spawn(async move {
-                        Self::handle_client(socket_arc, sender, sockets, client_count, addr).await;
+                        Self::handle_client(socket, client, addr).await;
                     });
                 }
             }
-        });
+        }));
 
-        server
+        Ok(local_addr)
     }
 
     // Handle an individual client connection
-    async fn handle_client(
-        socket: Arc<Mutex<TcpStream>>, // Wrapped in Arc<Mutex<>>
-        receiver: broadcast::Sender<Bytes>,
-        sockets: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>>,
-        client_count: Arc<AtomicUsize>,
-        addr: SocketAddr,
-    ) {
-        let mut receiver = receiver.clone().subscribe();
+    async fn handle_client(mut socket: TcpStream, client: ClientContext, addr: SocketAddr) {
+        // Only clients that pass the handshake are counted and receive frames
+        let local_hello = Hello {
+            channels: client.channels.lock().unwrap().iter().map(|channel| channel.name.clone()).collect(),
+            ..Hello::local()
+        };
+        let hello = match timeout(HANDSHAKE_TIMEOUT, protocol::handshake(&mut socket, &local_hello)).await {
+            Ok(Ok(hello)) => hello,
+            Ok(Err(e)) => {
+                eprintln!("Handshake with {} failed: {}", addr, e);
+                return;
+            }
+            Err(_) => {
+                eprintln!("Handshake with {} timed out", addr);
+                return;
+            }
+        };
+
+        let channel = match Self::find_channel(&client, hello.channel.as_deref()) {
+            Ok(channel) => channel,
+            Err(reason) => {
+                eprintln!("Turning away client {}: {}", addr, reason);
+                if let Some(goodbye) = Self::packet(&Message::Goodbye(reason)) {
+                    let _ = socket.write_all(&goodbye.data).await;
+                }
+                return;
+            }
+        };
+        println!("Client {} joined channel {}", addr, channel.name);
+
+        // Bring the client up to date before it joins the broadcast
+        let mut tier = Tier::Full;
+        let (mut receiver, mut pending, mut joined_at) = channel.join(tier);
+
+        let socket = Arc::new(Mutex::new(socket));
+        client.sockets.lock().await.insert(addr, Arc::clone(&socket));
+        let stats = ClientStats { channel: channel.name.clone(), ..Default::default() };
+        client.stats.lock().unwrap().insert(addr, stats);
+        client.client_count.fetch_add(1, Ordering::SeqCst);
+        channel.tier_clients[tier.index()].fetch_add(1, Ordering::SeqCst);
+        let mut awaiting_keyframe = Self::needs_keyframe(&channel, tier, &pending);
 
+        // Throughput is measured over fixed windows, and a reduced client
+        // must keep up for a while before it is trusted with full quality again
+        let mut window_start = Instant::now();
+        let mut window_bytes = 0u64;
+        let mut window_tier_bytes = channel.bytes_sent[tier.index()].load(Ordering::SeqCst);
+        let mut falling_behind = false; // Wrote less than the tier sent over the last window
+        let mut keeping_up_since = Instant::now();
+        let mut frames_taken = 0u64; // Frames of the tier taken off the broadcast since joining
+
+        let ping = Self::packet(&Message::Ping).expect("Ping always serializes");
         loop {
-            match receiver.recv().await {
-                Ok(frame) => {
-                    let mut socket = socket.lock().await;
-                    if socket.write_all(&frame).await.is_err() {
-                        break;
+            let mut lagged = false;
+            let packet = if let Some(packet) = pending.pop_front() {
+                packet
+            } else {
+                match timeout(PING_INTERVAL, receiver.recv()).await {
+                    Ok(Ok(packet)) => {
+                        if packet.codec.is_some() && packet.tier == Some(tier) {
+                            frames_taken += 1;
+                        }
+                        packet
+                    }
+                    Ok(Err(RecvError::Lagged(missed))) => {
+                        // Too slow for the backlog: the rest of it is dropped below
+                        let skipped = missed + receiver.len() as u64;
+                        if let Some(stats) = client.stats.lock().unwrap().get_mut(&addr) {
+                            stats.skipped += skipped;
+                            stats.resyncs += 1;
+                        }
+                        lagged = true;
+                        ping.clone()
                     }
+                    Ok(Err(RecvError::Closed)) => break,
+                    Err(_) => ping.clone(),
+                }
+            };
+
+            // Frames of the other tier are not for this client
+            if packet.tier.is_some_and(|packet_tier| packet_tier != tier) {
+                continue;
+            }
+
+            // Pick the tier this client can keep up with. Only frames of its own tier count,
+            // pointer messages are small and frequent and say nothing about the link.
+            let queue_depth = channel.frames_sent[tier.index()].load(Ordering::SeqCst).saturating_sub(joined_at + frames_taken);
+            let new_tier = match tier {
+                Tier::Full if lagged || queue_depth > DEGRADE_QUEUE_DEPTH || falling_behind => Tier::Reduced,
+                Tier::Reduced if keeping_up_since.elapsed() >= UPGRADE_AFTER => Tier::Full,
+                _ => tier,
+            };
+            if lagged || queue_depth > 2 || falling_behind { // A couple of queued frames is normal while one is written
+                keeping_up_since = Instant::now();
+            }
+            if new_tier != tier || lagged {
+                if new_tier != tier {
+                    println!("Client {} moved to the {} tier", addr, new_tier.name());
+                    channel.tier_clients[tier.index()].fetch_sub(1, Ordering::SeqCst);
+                    channel.tier_clients[new_tier.index()].fetch_add(1, Ordering::SeqCst);
+                    tier = new_tier;
+                    keeping_up_since = Instant::now();
+                    // The new tier's rate is measured from here
+                    window_start = Instant::now();
+                    window_bytes = 0;
+                    window_tier_bytes = channel.bytes_sent[tier.index()].load(Ordering::SeqCst);
+                    falling_behind = false;
+                }
+                // Jump to the newest message, starting over from the tier's current image.
+                // Deltas against skipped frames, or from another tier, are useless.
+                (receiver, pending, joined_at) = channel.join(tier);
+                frames_taken = 0;
+                awaiting_keyframe = Self::needs_keyframe(&channel, tier, &pending);
+                continue;
+            }
+
+            if packet.codec.is_some() {
+                if awaiting_keyframe && !packet.keyframe {
+                    if let Some(stats) = client.stats.lock().unwrap().get_mut(&addr) {
+                        stats.skipped += 1;
+                    }
+                    continue;
+                }
+                awaiting_keyframe = false;
+            }
+
+            let mut socket = socket.lock().await;
+            if let Some(reason) = Self::unacceptable(&hello, &packet) {
+                eprintln!("Dropping client {}: {}", addr, reason);
+                if let Some(goodbye) = Self::packet(&Message::Goodbye(reason)) {
+                    let _ = socket.write_all(&goodbye.data).await;
+                }
+                break;
+            }
+            if socket.write_all(&packet.data).await.is_err() || packet.last {
+                break;
+            }
+
+            window_bytes += packet.data.len() as u64;
+            if let Some(stats) = client.stats.lock().unwrap().get_mut(&addr) {
+                stats.bytes_sent += packet.data.len() as u64;
+                stats.queue_depth = queue_depth;
+                stats.tier = tier;
+                let elapsed = window_start.elapsed();
+                if elapsed >= THROUGHPUT_WINDOW {
+                    stats.throughput_kbps = (window_bytes as f64 * 8.0 / 1000.0 / elapsed.as_secs_f64()) as u32;
+                    // Frames piling up while the tier sends more than the client writes is a
+                    // slow link, not a single large frame on its way out
+                    let tier_bytes = channel.bytes_sent[tier.index()].load(Ordering::SeqCst);
+                    falling_behind = queue_depth > 2 && window_bytes < tier_bytes - window_tier_bytes;
+                    window_start = Instant::now();
+                    window_bytes = 0;
+                    window_tier_bytes = tier_bytes;
                 }
-                Err(_) => break, // Channel closed
             }
         }
 
         println!("Client disconnected: {}", addr);
-        sockets.lock().await.remove(&addr);
+        client.stats.lock().unwrap().remove(&addr);
+        channel.tier_clients[tier.index()].fetch_sub(1, Ordering::SeqCst);
+        let client_count = client.client_count;
+        client.sockets.lock().await.remove(&addr);
         let mut current_value = client_count.load(Ordering::SeqCst);
         while current_value > 0 {
             let new_value = current_value - 1;
-            if let Ok(_) = client_count.compare_exchange(current_value, new_value, Ordering::SeqCst, Ordering::SeqCst) {
+            if client_count.compare_exchange(current_value, new_value, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
                 break;  
             }
             current_value = client_count.load(Ordering::SeqCst);
         }
     }
 
-    // Broadcast a frame to all connected clients
-    pub fn broadcast_frame(&mut self, frame: Frame, is_streaming:bool) {
-        if self.priority.load(Ordering::SeqCst) {
-            return;
+    // The channel a client asked for, or the first one if it didn't ask
+    fn find_channel(client: &ClientContext, name: Option<&str>) -> Result<Arc<ChannelShared>, String> {
+        let channels = client.channels.lock().unwrap();
+        let found = match name {
+            Some(name) => channels.iter().find(|channel| channel.name == name),
+            None => channels.first(),
+        };
+        match (found, name) {
+            (Some(channel), _) => Ok(Arc::clone(channel)),
+            (None, _) if channels.is_empty() => Err("The caster is not sharing anything yet".to_string()),
+            (None, Some(name)) => Err(format!(
+                "No channel named {}, available: {}",
+                name,
+                channels.iter().map(|channel| channel.name.as_str()).collect::<Vec<_>>().join(", ")
+            )),
+            (None, None) => unreachable!("the first channel exists when the list is not empty"),
         }
-        let now = Instant::now();
-        if now.duration_since(self.time) >= Duration::from_millis(60){
-            if is_streaming{
-                let serialized_frame = match bincode::serialize(&frame) {
-                    Ok(data) => data,
-                    Err(e) => {
-                        eprintln!("Failed to serialize frame: {}", e);
-                        return;
-                    }
-                };
-                let frame_size = (serialized_frame.len() as u32).to_be_bytes();
-        
-                // Prepare the buffer with size + serialized data
-                let mut buffer = Vec::with_capacity(4 + serialized_frame.len());
-                buffer.extend_from_slice(&frame_size);      // Frame size (4 bytes)
-                buffer.extend_from_slice(&serialized_frame);     // Add the frame data
-        
-                let _ = self.sender.send(Bytes::from(buffer));
+    }
+
+    // Whether the replayed history lacks a keyframe, in which case the tier is asked for one
+    fn needs_keyframe(channel: &ChannelShared, tier: Tier, pending: &VecDeque<Packet>) -> bool {
+        let missing = !pending.iter().any(|packet| packet.keyframe);
+        if missing {
+            channel.keyframe_requested[tier.index()].store(true, Ordering::SeqCst);
+        }
+        missing
+    }
+
+    // Why a client can't be sent this packet, according to what it announced in its hello
+    fn unacceptable(hello: &Hello, packet: &Packet) -> Option<String> {
+        if let Some(codec) = packet.codec {
+            if !hello.codecs.contains(&codec) {
+                return Some(format!("This receiver cannot decode {} frames", codec.name()));
             }
-            else {
-                // Send only the size prefix of 0 (4 bytes)
-                let frame_size = (0 as u32).to_be_bytes();
-                let mut buffer = Vec::with_capacity(4);
-                buffer.extend_from_slice(&frame_size); 
-    
-                let _ = self.sender.send(Bytes::from(buffer));
+        }
+        let size = packet.data.len() - 4; // Without the length prefix
+        if size > hello.max_message_size as usize {
+            return Some(format!(
+                "Message of {} bytes exceeds the receiver's limit of {} bytes",
+                size,
+                hello.max_message_size
+            ));
+        }
+        if packet.pixels > hello.max_frame_pixels {
+            return Some(format!(
+                "Frames of {} pixels exceed the receiver's limit of {} pixels",
+                packet.pixels, hello.max_frame_pixels
+            ));
+        }
+        None
+    }
+
+    fn packet(message: &Message) -> Option<Packet> {
+        let (codec, keyframe, pixels) = match message {
+            Message::Frame(frame) => (Some(frame.codec), frame.keyframe, frame.width as u64 * frame.height as u64),
+            _ => (None, false, 0),
+        };
+        match protocol::encode_message(message) {
+            Ok(data) => Some(Packet { codec, keyframe, pixels, tier: None, last: false, data: Bytes::from(data) }),
+            Err(e) => {
+                eprintln!("Failed to serialize message: {}", e);
+                None
             }
-            self.time = now;
         }
+    }
+
+    // A message only for the clients of one tier
+    fn tier_packet(tier: Tier, message: &Message) -> Option<Packet> {
+        let mut packet = Self::packet(message)?;
+        packet.tier = Some(tier);
+        Some(packet)
+    }
+
+    // Add a channel for a new source. The name is made unique if needed, see ChannelSender::name.
+    pub fn add_channel(&mut self, name: &str) -> ChannelSender {
+        let mut unique = name.to_string();
+        let mut number = 2;
+        while self.channels.iter().any(|channel| channel.name == unique) {
+            unique = format!("{} ({})", name, number);
+            number += 1;
+        }
+        let mut channel = Channel::new(unique.clone());
+        channel.apply_settings(self.codec, self.quality, self.h264_settings);
+        self.directory.lock().unwrap().push(Arc::clone(&channel.shared));
+        let sender = ChannelSender {
+            name: unique,
+            channel: Arc::new(std::sync::Mutex::new(channel)),
+            priority: Arc::clone(&self.priority),
+        };
+        self.channels.push(sender.clone());
+        sender
+    }
+
+    // Stop a channel and say goodbye to the clients watching it
+    pub fn remove_channel(&mut self, name: &str) {
+        self.directory.lock().unwrap().retain(|channel| channel.name != name);
+        if let Some(index) = self.channels.iter().position(|channel| channel.name == name) {
+            let channel = self.channels.remove(index);
+            if let Some(mut goodbye) = Self::packet(&Message::Goodbye(format!("The caster stopped sharing {}", name))) {
+                goodbye.last = true;
+                let _ = channel.channel.lock().unwrap().shared.sender.send(goodbye);
+            }
+        }
+    }
+
+    // Start a channel over from its next frame, so clients that join later never
+    // see what was sent before, e.g. pixels a new privacy mask now covers
+    pub fn reset_channel(&self, name: &str) {
+        if let Some(channel) = self.channels.iter().find(|channel| channel.name == name) {
+            channel.channel.lock().unwrap().shared.reset();
+        }
+    }
+
+    // Select how frames are compressed from the next frame on
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+        self.apply_settings();
+    }
+
+    // Bitrate and keyframe spacing used by the H.264 codec
+    pub fn set_h264_settings(&mut self, settings: H264Settings) {
+        self.h264_settings = settings;
+        self.apply_settings();
+    }
+
+    // Quality (1-100) used by the lossy codecs
+    pub fn set_quality(&mut self, quality: u8) {
+        self.quality = quality;
+        self.apply_settings();
+    }
 
+    fn apply_settings(&mut self) {
+        for channel in &self.channels {
+            channel.channel.lock().unwrap().apply_settings(self.codec, self.quality, self.h264_settings);
+        }
     }
 
     // Disconnect all clients
@@ -154,10 +771,15 @@ impl StreamServer {
             let mut sockets = self.sockets.lock().await;
             let addr_list: Vec<SocketAddr> = sockets.keys().cloned().collect();
         
+            let goodbye = Self::packet(&Message::Goodbye("The caster ended the session".to_string()));
+
             // Iterate over each socket and perform shutdown synchronously
             for addr in addr_list {
                 if let Some(socket) = sockets.remove(&addr) {
                     let mut socket = socket.lock().await;
+                    if let Some(goodbye) = &goodbye {
+                        let _ = socket.write_all(&goodbye.data).await;
+                    }
                     if let Err(e) = socket.shutdown().await {
                         eprintln!("Failed to close socket {}: {}", addr, e);
                     }
@@ -174,4 +796,85 @@ impl StreamServer {
     pub fn get_client_count(&self) -> usize {
         self.client_count.load(Ordering::SeqCst)
     }
-}
\ No newline at end of file
+
+    // Per-client counters, ordered by address
+    pub fn client_stats(&self) -> Vec<(SocketAddr, ClientStats)> {
+        let mut stats: Vec<_> = self.stats.lock().unwrap().iter().map(|(addr, stats)| (*addr, stats.clone())).collect();
+        stats.sort_by_key(|(addr, _)| *addr);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(address: &str, ip_version: IpVersion) -> ServerConfig {
+        ServerConfig { address: address.to_string(), ip_version, port: 9000 }
+    }
+
+    #[test]
+    fn empty_address_listens_everywhere() {
+        assert_eq!(config("", IpVersion::V4).socket_addr(), Ok("0.0.0.0:9000".parse().unwrap()));
+        assert_eq!(config(" ", IpVersion::V6).socket_addr(), Ok("[::]:9000".parse().unwrap()));
+    }
+
+    #[test]
+    fn address_of_the_selected_version_is_used() {
+        assert_eq!(config("127.0.0.1", IpVersion::V4).socket_addr(), Ok("127.0.0.1:9000".parse().unwrap()));
+        assert_eq!(config("::1", IpVersion::V6).socket_addr(), Ok("[::1]:9000".parse().unwrap()));
+    }
+
+    #[test]
+    fn mismatched_or_invalid_addresses_are_rejected() {
+        assert!(config("127.0.0.1", IpVersion::V6).socket_addr().is_err());
+        assert!(config("::1", IpVersion::V4).socket_addr().is_err());
+        assert!(config("caster.local", IpVersion::V4).socket_addr().is_err());
+        assert!(config("127.0.0.1:9000", IpVersion::V4).socket_addr().is_err());
+    }
+
+    // A frame that differs from the previous one in a single pixel
+    fn frame(number: u8) -> Frame {
+        let mut data = vec![0; 64 * 64 * 4];
+        data[0] = number;
+        Frame { data, width: 64, height: 64 }
+    }
+
+    #[test]
+    fn join_replays_from_the_last_keyframe() {
+        let mut channel = Channel::new("test".to_string());
+        for number in 0..3 {
+            channel.broadcast_tier(Tier::Full, &frame(number));
+        }
+        let (_, pending, _) = channel.shared.join(Tier::Full);
+        assert!(pending[0].codec.is_none(), "metadata comes first");
+        let frames: Vec<&Packet> = pending.iter().skip(1).collect();
+        assert_eq!(frames.len(), 3);
+        assert!(frames[0].keyframe);
+        assert!(frames[1..].iter().all(|packet| !packet.keyframe));
+
+        // A new keyframe starts the history over
+        channel.shared.keyframe_requested[Tier::Full.index()].store(true, Ordering::SeqCst);
+        channel.broadcast_tier(Tier::Full, &frame(3));
+        let (_, pending, _) = channel.shared.join(Tier::Full);
+        assert_eq!(pending.len(), 2);
+        assert!(pending[1].keyframe);
+
+        // The other tier has nothing to replay
+        let (_, pending, _) = channel.shared.join(Tier::Reduced);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn reset_leaves_nothing_to_replay_until_the_next_keyframe() {
+        let mut channel = Channel::new("test".to_string());
+        channel.broadcast_tier(Tier::Full, &frame(0));
+        channel.broadcast_tier(Tier::Full, &frame(1));
+        channel.shared.reset();
+        let (_, pending, _) = channel.shared.join(Tier::Full);
+        assert!(pending.iter().all(|packet| packet.codec.is_none()));
+        channel.broadcast_tier(Tier::Full, &frame(2));
+        let (_, pending, _) = channel.shared.join(Tier::Full);
+        assert!(pending.iter().find(|packet| packet.codec.is_some()).is_some_and(|packet| packet.keyframe));
+    }
+}
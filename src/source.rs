@@ -0,0 +1,203 @@
+use scrap::{Capturer, Display};
+use std::io;
+use crate::cursor::{Cursor, CursorReader};
+use crate::screen::{Frame, resize};
+
+// Anything that can produce RGBA frames for a ScreenCapture.
+// Sources are created on the capture thread, so they don't need to be Send.
+pub trait FrameSource {
+    // Return the next frame, or an error of kind WouldBlock if none is ready yet
+    fn next_frame(&mut self) -> io::Result<Frame>;
+
+    // Where the pointer is, for sources that can tell
+    fn cursor(&mut self) -> Option<Cursor> {
+        None
+    }
+}
+
+// A monitor that can be captured, listed in the same order scrap uses
+#[derive(Clone, PartialEq, Debug)]
+pub struct DisplayInfo {
+    // The output name on X11. Elsewhere scrap only gives the size, so the id is
+    // the size and only stable while no other monitor has the same one.
+    pub id: String,
+    pub stable: bool, // The id names this monitor across hot-plugs and restarts
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub x: i32,
+    pub y: i32,
+    pub primary: bool,
+}
+
+impl DisplayInfo {
+    pub fn label(&self) -> String {
+        let primary = if self.primary { ", primary" } else { "" };
+        format!("{} ({}x{} at {},{}{})", self.name, self.width, self.height, self.x, self.y, primary)
+    }
+}
+
+pub fn available_displays() -> Vec<DisplayInfo> {
+    // Polled for hot-plugs, so a missing RandR quietly falls back to scrap
+    #[cfg(target_os = "linux")]
+    if let Ok(displays) = randr::monitors() {
+        return displays;
+    }
+
+    // scrap only knows the size, so the first display stands in for the primary one.
+    // The index moves when monitors come and go, the size is the steadier id.
+    let sizes: Vec<(usize, usize)> = Display::all()
+        .unwrap_or_default()
+        .iter()
+        .map(|display| (display.width(), display.height()))
+        .collect();
+    sizes
+        .iter()
+        .enumerate()
+        .map(|(index, &(width, height))| {
+            let same_size = sizes.iter().filter(|&&size| size == (width, height)).count();
+            let before = sizes[..index].iter().filter(|&&size| size == (width, height)).count();
+            DisplayInfo {
+                // Monitors of the same size are told apart by their order, which is not stable
+                id: match before {
+                    0 => format!("display-{}x{}", width, height),
+                    _ => format!("display-{}x{}-{}", width, height, before + 1),
+                },
+                stable: same_size == 1,
+                name: format!("Monitor {}", index + 1),
+                width: width as u32,
+                height: height as u32,
+                x: 0,
+                y: 0,
+                primary: index == 0,
+            }
+        })
+        .collect()
+}
+
+// scrap lists the RandR monitors of every X screen, asking for the same
+// list gives their names and positions in scrap's order
+#[cfg(target_os = "linux")]
+mod randr {
+    use std::io;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::randr::ConnectionExt as _;
+    use x11rb::protocol::xproto::ConnectionExt as _;
+    use crate::xserver::{self, x11_error};
+    use super::DisplayInfo;
+
+    pub fn monitors() -> io::Result<Vec<DisplayInfo>> {
+        let (conn, _) = xserver::connect()?;
+        let mut displays = Vec::new();
+        for screen in &conn.setup().roots {
+            let reply = conn.randr_get_monitors(screen.root, true).map_err(x11_error)?.reply().map_err(x11_error)?;
+            for monitor in reply.monitors {
+                let index = displays.len();
+                let output = conn
+                    .get_atom_name(monitor.name)
+                    .ok()
+                    .and_then(|cookie| cookie.reply().ok())
+                    .map(|reply| String::from_utf8_lossy(&reply.name).into_owned());
+                displays.push(DisplayInfo {
+                    stable: output.is_some(),
+                    id: output.unwrap_or_else(|| format!("display-{}", index)),
+                    name: format!("Monitor {}", index + 1),
+                    width: monitor.width as u32,
+                    height: monitor.height as u32,
+                    x: monitor.x as i32,
+                    y: monitor.y as i32,
+                    primary: monitor.primary,
+                });
+            }
+        }
+        Ok(displays)
+    }
+}
+
+// A small picture of what a display shows, at most max_width wide
+pub fn display_thumbnail(id: &str, max_width: u32) -> io::Result<Frame> {
+    let mut source = ScrapSource::new(id)?;
+    // Some platforms need a moment before the first frame
+    for _ in 0..50 {
+        match source.next_frame() {
+            Ok(frame) => {
+                let width = max_width.min(frame.width);
+                let height = (frame.height as u64 * width as u64 / frame.width.max(1) as u64).max(1) as u32;
+                return Ok(resize(&frame, width, height));
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => std::thread::sleep(std::time::Duration::from_millis(10)),
+            Err(error) => return Err(error),
+        }
+    }
+    Err(io::ErrorKind::TimedOut.into())
+}
+
+pub(crate) fn convert_bgra_to_rgba(frame: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let h = height as usize;
+    let w = width as usize;
+    let stride = frame.len() / h;
+    let mut rgba_data = Vec::with_capacity((width * height * 4) as usize);
+
+    for y in 0..h {
+        for x in 0..w {
+            let i = stride * y + 4 * x;
+            rgba_data.extend_from_slice(&[
+                frame[i + 2],
+                frame[i + 1],
+                frame[i],
+                255,
+            ]);
+        }
+    }
+
+    rgba_data
+}
+
+// Captures a whole monitor through scrap
+pub struct ScrapSource {
+    capturer: Capturer,
+    width: u32,
+    height: u32,
+    cursor: Option<CursorReader>,
+    origin: (i32, i32), // The display's top-left corner on the desktop
+}
+
+impl ScrapSource {
+    // Open the display with this DisplayInfo id. Looked up by id, since the
+    // index of a monitor changes when others are plugged in or out.
+    pub fn new(id: &str) -> io::Result<Self> {
+        let available = available_displays();
+        let index = available
+            .iter()
+            .position(|display| display.id == id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Display {} not found", id)))?;
+        let origin = (available[index].x, available[index].y);
+        let mut displays = Display::all()?;
+        if index >= displays.len() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("Display {} not found", id)));
+        }
+        let display = displays.remove(index);
+        let capturer = Capturer::new(display)?;
+        let width = capturer.width() as u32;
+        let height = capturer.height() as u32;
+        // Capturing still works without the pointer
+        let cursor = CursorReader::new().ok();
+        Ok(Self { capturer, width, height, cursor, origin })
+    }
+}
+
+impl FrameSource for ScrapSource {
+    fn next_frame(&mut self) -> io::Result<Frame> {
+        let frame = self.capturer.frame()?;
+        Ok(Frame {
+            data: convert_bgra_to_rgba(&frame, self.width, self.height),
+            width: self.width,
+            height: self.height,
+        })
+    }
+
+    fn cursor(&mut self) -> Option<Cursor> {
+        let origin = self.origin;
+        self.cursor.as_mut()?.read(origin)
+    }
+}
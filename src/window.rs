@@ -0,0 +1,212 @@
+#[cfg(not(target_os = "linux"))]
+use std::io;
+use crate::screen::Frame;
+use crate::source::FrameSource;
+
+// A top-level window that can be captured on its own
+#[derive(Clone, PartialEq, Debug)]
+pub struct WindowInfo {
+    pub id: u32,
+    pub title: String,
+    pub class: Option<String>, // WM_CLASS, the application. Unlike the title it stays put.
+}
+
+#[cfg(target_os = "linux")]
+pub use x11::{available_windows, WindowSource};
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use std::io;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::composite::{ConnectionExt as _, Redirect};
+    use x11rb::protocol::xproto::{AtomEnum, ConnectionExt as _, ImageFormat, MapState, Pixmap, Window};
+    use x11rb::rust_connection::RustConnection;
+    use super::{Frame, FrameSource, WindowInfo};
+    use crate::cursor::{Cursor, CursorReader};
+    use crate::source::convert_bgra_to_rgba;
+    use crate::xserver::{self, x11_error};
+
+    // Windows listed by the window manager, or every viewable child of the root
+    // window when there is none (for example under Xvfb)
+    pub fn available_windows() -> Vec<WindowInfo> {
+        match list_windows() {
+            Ok(windows) => windows,
+            Err(error) => {
+                eprintln!("Failed to list windows: {}", error);
+                Vec::new()
+            }
+        }
+    }
+
+    fn list_windows() -> io::Result<Vec<WindowInfo>> {
+        let (conn, screen_num) = xserver::connect()?;
+        let root = conn.setup().roots[screen_num].root;
+
+        let client_list = intern(&conn, b"_NET_CLIENT_LIST")?;
+        let managed: Vec<Window> = conn
+            .get_property(false, root, client_list, AtomEnum::WINDOW, 0, u32::MAX)
+            .map_err(x11_error)?
+            .reply()
+            .map_err(x11_error)?
+            .value32()
+            .map(|windows| windows.collect())
+            .unwrap_or_default();
+        let candidates = if managed.is_empty() {
+            conn.query_tree(root).map_err(x11_error)?.reply().map_err(x11_error)?.children
+        } else {
+            managed
+        };
+
+        let mut windows = Vec::new();
+        for id in candidates {
+            let Ok(attributes) = conn.get_window_attributes(id).map_err(x11_error)?.reply() else {
+                continue; // Closed while listing
+            };
+            if attributes.map_state != MapState::VIEWABLE || attributes.override_redirect {
+                continue;
+            }
+            if let Some(title) = window_title(&conn, id)? {
+                let class = window_class(&conn, id)?;
+                windows.push(WindowInfo { id, title, class });
+            }
+        }
+        Ok(windows)
+    }
+
+    fn intern(conn: &RustConnection, name: &[u8]) -> io::Result<u32> {
+        Ok(conn.intern_atom(false, name).map_err(x11_error)?.reply().map_err(x11_error)?.atom)
+    }
+
+    // _NET_WM_NAME is UTF-8, the older WM_NAME is used when it is missing
+    fn window_title(conn: &RustConnection, window: Window) -> io::Result<Option<String>> {
+        let net_wm_name = intern(conn, b"_NET_WM_NAME")?;
+        let utf8_string = intern(conn, b"UTF8_STRING")?;
+        for (property, kind) in [(net_wm_name, utf8_string), (AtomEnum::WM_NAME.into(), AtomEnum::ANY.into())] {
+            let Ok(reply) = conn.get_property(false, window, property, kind, 0, 1024).map_err(x11_error)?.reply() else {
+                return Ok(None);
+            };
+            let title = String::from_utf8_lossy(&reply.value).trim().to_string();
+            if !title.is_empty() {
+                return Ok(Some(title));
+            }
+        }
+        Ok(None)
+    }
+
+    // WM_CLASS holds the instance and class names, each ending in a NUL
+    fn window_class(conn: &RustConnection, window: Window) -> io::Result<Option<String>> {
+        let Ok(reply) = conn
+            .get_property(false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 1024)
+            .map_err(x11_error)?
+            .reply()
+        else {
+            return Ok(None);
+        };
+        let mut names = reply.value.split(|&byte| byte == 0).filter(|name| !name.is_empty());
+        let instance = names.next();
+        // The class is the application, the instance can be changed per window
+        Ok(names.next().or(instance).map(|name| String::from_utf8_lossy(name).into_owned()))
+    }
+
+    // Captures one window. With the Composite extension the window's own pixmap is read,
+    // so it is captured correctly even while other windows cover it.
+    pub struct WindowSource {
+        conn: RustConnection,
+        window: Window,
+        composite: bool,
+        cursor: Option<CursorReader>,
+    }
+
+    impl WindowSource {
+        pub fn new(window: u32) -> io::Result<Self> {
+            let (conn, _) = xserver::connect()?;
+            let composite = conn
+                .composite_query_version(0, 4)
+                .ok()
+                .and_then(|cookie| cookie.reply().ok())
+                .is_some();
+            if composite {
+                conn.composite_redirect_window(window, Redirect::AUTOMATIC).map_err(x11_error)?;
+            } else {
+                eprintln!("Composite extension not available, covered parts of the window will be captured as they appear");
+            }
+            conn.flush().map_err(x11_error)?;
+            Ok(Self { conn, window, composite, cursor: CursorReader::new().ok() })
+        }
+
+        fn read_image(&self, drawable: u32, width: u16, height: u16) -> io::Result<Frame> {
+            let image = self
+                .conn
+                .get_image(ImageFormat::Z_PIXMAP, drawable, 0, 0, width, height, u32::MAX)
+                .map_err(x11_error)?
+                .reply()
+                .map_err(x11_error)?;
+            // 24 and 32 bit visuals are both stored as 4 bytes per pixel, BGRX
+            if image.data.len() < width as usize * height as usize * 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Unsupported window depth {}", image.depth),
+                ));
+            }
+            Ok(Frame {
+                data: convert_bgra_to_rgba(&image.data, width as u32, height as u32),
+                width: width as u32,
+                height: height as u32,
+            })
+        }
+    }
+
+    impl FrameSource for WindowSource {
+        fn next_frame(&mut self) -> io::Result<Frame> {
+            // Looked up every frame, the window may have been resized
+            let geometry = self
+                .conn
+                .get_geometry(self.window)
+                .map_err(x11_error)?
+                .reply()
+                .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "The window was closed"))?;
+            if geometry.width == 0 || geometry.height == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+
+            if !self.composite {
+                return self.read_image(self.window, geometry.width, geometry.height);
+            }
+            // The pixmap is replaced whenever the window is resized, so name it anew each time
+            let pixmap: Pixmap = self.conn.generate_id().map_err(x11_error)?;
+            self.conn.composite_name_window_pixmap(self.window, pixmap).map_err(x11_error)?;
+            let frame = self.read_image(pixmap, geometry.width, geometry.height);
+            let _ = self.conn.free_pixmap(pixmap);
+            frame
+        }
+
+        fn cursor(&mut self) -> Option<Cursor> {
+            let reader = self.cursor.as_mut()?;
+            // The window may have been moved since the last frame
+            let origin = reader.window_origin(self.window)?;
+            reader.read(origin)
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn available_windows() -> Vec<WindowInfo> {
+    Vec::new()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct WindowSource;
+
+#[cfg(not(target_os = "linux"))]
+impl WindowSource {
+    pub fn new(_window: u32) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Window capture is only supported on X11"))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl FrameSource for WindowSource {
+    fn next_frame(&mut self) -> io::Result<Frame> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
@@ -0,0 +1,12 @@
+// What the X11 code in window, source and cursor has in common
+use std::io;
+use x11rb::rust_connection::RustConnection;
+
+pub fn x11_error(error: impl std::fmt::Display) -> io::Error {
+    io::Error::other(format!("X11: {}", error))
+}
+
+// A new connection to the display in $DISPLAY, along with its default screen number
+pub fn connect() -> io::Result<(RustConnection, usize)> {
+    x11rb::connect(None).map_err(x11_error)
+}